@@ -0,0 +1,688 @@
+//! Bidirectional path tracing. Each sample traces a subpath from the camera and
+//! one from a light, then connects every vertex of the first to every vertex of
+//! the second. The different ways of building the same path are weighted against
+//! each other by multiple importance sampling (Veach, "Robust Monte Carlo Methods
+//! for Light Transport Simulation", 1997, chapter 10).
+
+use nalgebra::Vector3;
+
+use crate::engine::{
+    pick_lobe, Engine, Integrator, RenderSettings, WorkerState, BSDF_DIMENSION, LIGHT_DIMENSION,
+    LIGHT_PICK_DIMENSION, LOBE_DIMENSION, RUSSIAN_ROULETTE_DIMENSION,
+};
+use crate::film::{Film, FilmPixel};
+use crate::objects::{uniform_sphere, ObjectsTrait};
+use crate::precision::{consts::PI, Real, RAY_EPSILON};
+use crate::ray::Ray;
+use crate::sampler::{bounce_dimension, Sampler};
+use crate::spectrum::REFERENCE_WAVELENGTH;
+use crate::texture_material::TextureMaterial;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum VertexKind {
+    Camera,
+    // Start of a light subpath on a point light of the given intensity
+    PointLight(Vector3<Real>),
+    // Start of a light subpath on an emissive surface
+    AreaLight,
+    Surface,
+}
+
+#[derive(Copy, Clone)]
+struct Vertex<'a> {
+    kind: VertexKind,
+    point: Vector3<Real>,
+    // Geometric normal, zero for vertices which aren't on a surface
+    normal: Vector3<Real>,
+    // Contribution of the subpath up to the vertex, over the density of sampling it
+    throughput: Vector3<Real>,
+    // Densities with respect to area of sampling the vertex from the previous one
+    // of its subpath, and from the next one when the path is traced the other way
+    pdf_forward: Real,
+    pdf_reverse: Real,
+    // The subpath left through a specular lobe, it can't be connected here
+    delta: bool,
+    object: Option<&'a dyn ObjectsTrait>,
+}
+
+impl<'a> Vertex<'a> {
+    fn texture(&self) -> TextureMaterial {
+        self.object
+            .map(|object| object.get_texture())
+            .unwrap_or_default()
+    }
+
+    /// Whether a connection can end at the vertex, through the diffuse lobe for surfaces.
+    fn connectible(&self) -> bool {
+        match self.kind {
+            VertexKind::Surface => self.texture().surface.diffuse.kd > 0.,
+            _ => true,
+        }
+    }
+
+    /// Radiance emitted by the surface, intensity for point lights. Surfaces emit
+    /// on both sides like in `trace_path`.
+    fn emitted(&self) -> Vector3<Real> {
+        match self.kind {
+            VertexKind::PointLight(intensity) => intensity,
+            VertexKind::Camera => Vector3::zeros(),
+            _ => {
+                let TextureMaterial { color, surface } = self.texture();
+                color * surface.emittance.map(|e| e.ke).unwrap_or(0.)
+            }
+        }
+    }
+
+    /// Cosine between the normal and `direction`, one for vertices off surfaces.
+    fn cos(&self, direction: &Vector3<Real>) -> Real {
+        if self.object.is_some() {
+            self.normal.dot(direction).abs()
+        } else {
+            1.
+        }
+    }
+
+    /// Convert the density `pdf`, with respect to solid angle, of going from this
+    /// vertex towards `next` into a density with respect to the area around `next`.
+    fn area_density(&self, pdf: Real, next: &Vertex) -> Real {
+        let to_next = next.point - self.point;
+        let distance_squared = to_next.norm_squared();
+        if distance_squared == 0. {
+            return 0.;
+        }
+
+        pdf * next.cos(&(to_next / distance_squared.sqrt())) / distance_squared
+    }
+
+    /// Diffuse lobe scattering light between `previous` and `next`, the only one
+    /// which can be evaluated for a given pair of directions.
+    fn bsdf(&self, previous: &Vector3<Real>, next: &Vector3<Real>) -> Vector3<Real> {
+        let wo = previous - self.point;
+        let wi = next - self.point;
+        if self.normal.dot(&wo) * self.normal.dot(&wi) <= 0. {
+            return Vector3::zeros();
+        }
+
+        let TextureMaterial { color, surface } = self.texture();
+        color * surface.diffuse.kd / (2. * PI)
+    }
+}
+
+/// Samples every pixel like the pathtracer, and splats the light paths reaching
+/// the camera on the whole image.
+pub struct Bidirectional;
+
+impl Integrator for Bidirectional {
+    fn render_tile(
+        &self,
+        engine: &Engine,
+        settings: &RenderSettings,
+        film: &Film,
+        _pass: u32,
+        tile_index: usize,
+        worker: &mut WorkerState,
+    ) -> usize {
+        let sampler = worker.sampler.as_mut();
+        let splats = &mut worker.splats;
+        splats.clear();
+
+        let samples = film.render_tile(
+            tile_index,
+            &mut worker.pixels,
+            &mut worker.accumulated,
+            |x, y, accumulated| {
+                let mut pixel = FilmPixel::default();
+                let first_sample = accumulated.weight as u32;
+                let samples = Engine::pixel_samples(settings, accumulated);
+                for sample in first_sample..first_sample + samples {
+                    sampler.start_sample(x, y, sample);
+                    pixel.add_sample(engine.trace_bidirectional(settings, x, y, sampler, splats));
+                }
+                pixel
+            },
+        );
+
+        // Every sample traced one light path
+        film.add_splats(tile_index, splats, samples as u64);
+        samples
+    }
+}
+
+impl Engine {
+    /// Radiance reaching the camera through pixel (`x`, `y`), light tracing
+    /// contributions to any pixel are pushed to `splats` with the pixel offset.
+    pub fn trace_bidirectional(
+        &self,
+        settings: &RenderSettings,
+        x: usize,
+        y: usize,
+        sampler: &mut dyn Sampler,
+        splats: &mut Vec<(usize, Vector3<Real>)>,
+    ) -> Vector3<Real> {
+        let max_depth = settings.max_depth as usize;
+        let mut camera_path = Vec::with_capacity(max_depth + 1);
+        let mut light_path = Vec::with_capacity(max_depth);
+
+        let ray = self.camera.create_ray(x, y, sampler);
+        let pdf = self
+            .camera
+            .project(&ray.direction)
+            .map(|(_, _, pdf)| pdf)
+            .unwrap_or(0.);
+        camera_path.push(Vertex {
+            kind: VertexKind::Camera,
+            point: ray.origin,
+            normal: Vector3::zeros(),
+            throughput: Vector3::repeat(1.),
+            pdf_forward: 1.,
+            pdf_reverse: 0.,
+            delta: false,
+            object: None,
+        });
+        self.random_walk(
+            settings,
+            ray,
+            Vector3::repeat(1.),
+            pdf,
+            &mut camera_path,
+            max_depth + 1,
+            sampler,
+            0,
+        );
+
+        self.light_subpath(settings, &mut light_path, sampler);
+
+        let mut radiance = Vector3::zeros();
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                // Paths are at most `max_depth` rays long, like in `trace_path`. Lights
+                // seen by the camera are only found by the camera subpath
+                if s + t < 2 || (s == 1 && t == 1) || s + t - 1 > max_depth {
+                    continue;
+                }
+
+                let (contribution, splat) = self.connect(&light_path, &camera_path, s, t);
+                if contribution == Vector3::zeros() {
+                    continue;
+                }
+
+                let weight = self.mis_weight(settings, &mut light_path, &mut camera_path, s, t);
+                match splat {
+                    Some(offset) => splats.push((offset, contribution * weight)),
+                    None => radiance += contribution * weight,
+                }
+            }
+        }
+
+        radiance
+    }
+
+    /// Start a subpath on a light picked uniformly among the emitters and point
+    /// lights, then continue it through the scene.
+    fn light_subpath<'a>(
+        &'a self,
+        settings: &RenderSettings,
+        path: &mut Vec<Vertex<'a>>,
+        sampler: &mut dyn Sampler,
+    ) {
+        let light_count = self.emitters.len() + self.lights.len();
+        if light_count == 0 || settings.max_depth == 0 {
+            return;
+        }
+
+        // Light subpaths use the dimensions after the longest camera subpath
+        let first_bounce = settings.max_depth + 1;
+        let dimension = bounce_dimension(first_bounce);
+
+        let pick = sampler.get_1d(dimension + LIGHT_PICK_DIMENSION);
+        let pick = ((pick * light_count as Real) as usize).min(light_count - 1);
+        let pick_pdf = 1. / light_count as Real;
+        let (u1, u2) = sampler.get_2d(dimension + BSDF_DIMENSION);
+
+        let (vertex, ray, pdf) = if pick < self.emitters.len() {
+            let object = &self.objects[self.emitters[pick]];
+            let (point, normal) =
+                match object.sample_area(sampler.get_2d(dimension + LIGHT_DIMENSION)) {
+                    Some(sample) => sample,
+                    None => return,
+                };
+
+            // Cosine weighted direction on a side picked at random
+            let (side, u1) = if u1 < 0.5 {
+                (normal, 2. * u1)
+            } else {
+                (-normal, 2. * u1 - 1.)
+            };
+            let (direction, cos_theta) = self.sample_hemisphere(side, (u1.sqrt(), u2));
+            if cos_theta <= 0. {
+                return;
+            }
+
+            let vertex = Vertex {
+                kind: VertexKind::AreaLight,
+                point,
+                normal,
+                throughput: Vector3::repeat(object.area() / pick_pdf),
+                pdf_forward: pick_pdf / object.area(),
+                pdf_reverse: 0.,
+                delta: false,
+                object: Some(object.as_ref()),
+            };
+            let ray = Ray::new(point + side * RAY_EPSILON, direction);
+            (vertex, ray, cos_theta / (2. * PI))
+        } else {
+            let light = &self.lights[pick - self.emitters.len()];
+
+            let direction = uniform_sphere((u1, u2));
+
+            let vertex = Vertex {
+                kind: VertexKind::PointLight(light.intensity * light.color),
+                point: light.position,
+                normal: Vector3::zeros(),
+                throughput: Vector3::repeat(1. / pick_pdf),
+                pdf_forward: pick_pdf,
+                pdf_reverse: 0.,
+                delta: false,
+                object: None,
+            };
+            (vertex, Ray::new(light.position, direction), 1. / (4. * PI))
+        };
+
+        let throughput =
+            vertex.throughput.component_mul(&vertex.emitted()) * vertex.cos(&ray.direction) / pdf;
+        path.push(vertex);
+        self.random_walk(
+            settings,
+            ray,
+            throughput,
+            pdf,
+            path,
+            settings.max_depth as usize,
+            sampler,
+            first_bounce + 1,
+        );
+    }
+
+    /// Extend `path` by following `ray`, picked with density `pdf` from the last
+    /// vertex, and scattering like `trace_path` until the path has `max_vertices`
+    /// vertices or is ended by russian roulette. Each vertex also gets the density
+    /// of sampling its predecessor the other way.
+    #[allow(clippy::too_many_arguments)]
+    fn random_walk<'a>(
+        &'a self,
+        settings: &RenderSettings,
+        mut ray: Ray,
+        mut throughput: Vector3<Real>,
+        mut pdf: Real,
+        path: &mut Vec<Vertex<'a>>,
+        max_vertices: usize,
+        sampler: &mut dyn Sampler,
+        first_bounce: u32,
+    ) {
+        for bounce in 0.. {
+            if path.len() >= max_vertices {
+                break;
+            }
+
+            let (record, object) = match self.get_closest_hit(
+                &ray,
+                self.camera.near_clipping_range,
+                self.camera.far_clipping_range,
+            ) {
+                Some(hit) => hit,
+                None => break,
+            };
+
+            let previous = path.len() - 1;
+            let mut vertex = Vertex {
+                kind: VertexKind::Surface,
+                point: record.point,
+                normal: record.normal,
+                throughput,
+                pdf_forward: 0.,
+                pdf_reverse: 0.,
+                delta: false,
+                object: Some(object.as_ref()),
+            };
+            vertex.pdf_forward = path[previous].area_density(pdf, &vertex);
+            path.push(vertex);
+            if path.len() >= max_vertices {
+                break;
+            }
+
+            let dimension = bounce_dimension(first_bounce + bounce);
+
+            if bounce + 1 >= settings.min_depth {
+                let survival = throughput.max().min(1.);
+                if sampler.get_1d(dimension + RUSSIAN_ROULETTE_DIMENSION) >= survival {
+                    break;
+                }
+                throughput /= survival;
+            }
+
+            let TextureMaterial { color, surface } = object.get_texture();
+            let (lobe_weights, refracted_ray) = self.scattering_lobes(
+                &surface,
+                record.point,
+                record.normal,
+                &ray,
+                REFERENCE_WAVELENGTH,
+            );
+            let total_weight: Real = lobe_weights.iter().sum();
+            if total_weight <= 0. {
+                break;
+            }
+
+            let relative_normal = if record.normal.dot(&ray.direction) < 0. {
+                record.normal
+            } else {
+                -record.normal
+            };
+            let wo = -ray.direction;
+
+            let lobe = pick_lobe(
+                &lobe_weights,
+                total_weight,
+                sampler.get_1d(dimension + LOBE_DIMENSION),
+            );
+            let (next_ray, pdf_reverse) = match (lobe, refracted_ray) {
+                (0, _) => {
+                    let (wi, cos_theta) = self.sample_hemisphere(
+                        relative_normal,
+                        sampler.get_2d(dimension + BSDF_DIMENSION),
+                    );
+                    throughput = throughput.component_mul(&color) * total_weight * cos_theta;
+                    pdf = lobe_weights[0] / total_weight / (2. * PI);
+
+                    let pdf_reverse = self.diffuse_pdf(&path[previous + 1], &wi, &wo);
+                    let next_ray = Ray::new(record.point + relative_normal * RAY_EPSILON, wi);
+                    (next_ray, pdf_reverse)
+                }
+                (1, _) => {
+                    throughput = throughput.component_mul(&color) * total_weight;
+                    let reflected_ray = Ray::new(
+                        record.point + (relative_normal * RAY_EPSILON),
+                        (ray.direction
+                            - (2.0 * ray.direction.dot(&relative_normal) * relative_normal))
+                            .normalize(),
+                    );
+                    (reflected_ray, 0.)
+                }
+                (_, Some(refracted_ray)) => {
+                    throughput *= total_weight;
+                    (refracted_ray, 0.)
+                }
+                // The refraction lobe has no weight without a refracted ray
+                (_, None) => unreachable!(),
+            };
+
+            // Specular lobes can't be sampled by a connection, their density is
+            // left at zero
+            if lobe != 0 {
+                path[previous + 1].delta = true;
+                pdf = 0.;
+            }
+            path[previous].pdf_reverse =
+                path[previous + 1].area_density(pdf_reverse, &path[previous]);
+            ray = next_ray;
+        }
+    }
+
+    /// Density with respect to solid angle of the walk leaving `vertex` in
+    /// direction `wi` through the diffuse lobe, when it arrived from `wo`.
+    fn diffuse_pdf(&self, vertex: &Vertex, wo: &Vector3<Real>, wi: &Vector3<Real>) -> Real {
+        if vertex.normal.dot(wo) * vertex.normal.dot(wi) <= 0. {
+            return 0.;
+        }
+
+        let surface = vertex.texture().surface;
+        let ray = Ray::new(vertex.point + wo, -wo);
+        let (lobe_weights, _) = self.scattering_lobes(
+            &surface,
+            vertex.point,
+            vertex.normal,
+            &ray,
+            REFERENCE_WAVELENGTH,
+        );
+        let total_weight: Real = lobe_weights.iter().sum();
+        if total_weight <= 0. {
+            return 0.;
+        }
+
+        lobe_weights[0] / total_weight / (2. * PI)
+    }
+
+    /// Density with respect to the area around `next` of sampling it from `vertex`,
+    /// when coming from `previous`.
+    fn vertex_pdf(&self, vertex: &Vertex, previous: Option<&Vertex>, next: &Vertex) -> Real {
+        let direction = (next.point - vertex.point).normalize();
+
+        let pdf = match (vertex.kind, previous) {
+            (VertexKind::Camera, _) => match self.camera.project(&direction) {
+                Some((_, _, pdf)) => pdf,
+                None => 0.,
+            },
+            (VertexKind::Surface, Some(previous)) => {
+                let wo = (previous.point - vertex.point).normalize();
+                self.diffuse_pdf(vertex, &wo, &direction)
+            }
+            _ => return self.emission_pdf(vertex, next),
+        };
+
+        vertex.area_density(pdf, next)
+    }
+
+    /// Density with respect to the area around `next` of a light subpath leaving
+    /// `vertex` towards it.
+    fn emission_pdf(&self, vertex: &Vertex, next: &Vertex) -> Real {
+        let direction = (next.point - vertex.point).normalize();
+        let pdf = match vertex.kind {
+            VertexKind::PointLight(_) => 1. / (4. * PI),
+            _ => vertex.cos(&direction) / (2. * PI),
+        };
+
+        vertex.area_density(pdf, next)
+    }
+
+    /// Density with respect to area of a light subpath starting at `vertex`, zero
+    /// if the vertex isn't on a light which can be sampled.
+    fn light_origin_pdf(&self, vertex: &Vertex) -> Real {
+        let pick_pdf = 1. / (self.emitters.len() + self.lights.len()) as Real;
+        match (vertex.kind, vertex.object) {
+            (VertexKind::PointLight(_), _) => pick_pdf,
+            (VertexKind::Camera, _) | (_, None) => 0.,
+            (_, Some(object)) if Self::is_sampled_emitter(object) => pick_pdf / object.area(),
+            _ => 0.,
+        }
+    }
+
+    /// Contribution of the path made of the first `s` vertices of the light
+    /// subpath and the first `t` of the camera subpath, with the pixel offset to
+    /// splat it on when it doesn't go through the pixel being rendered.
+    fn connect(
+        &self,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        s: usize,
+        t: usize,
+    ) -> (Vector3<Real>, Option<usize>) {
+        let none = (Vector3::zeros(), None);
+        let camera_vertex = &camera_path[t - 1];
+
+        // The camera subpath found an emitter by itself
+        if s == 0 {
+            if camera_vertex.kind != VertexKind::Surface {
+                return none;
+            }
+            return (
+                camera_vertex
+                    .throughput
+                    .component_mul(&camera_vertex.emitted()),
+                None,
+            );
+        }
+
+        let light_vertex = &light_path[s - 1];
+        if !light_vertex.connectible() || !camera_vertex.connectible() {
+            return none;
+        }
+
+        let to_light = light_vertex.point - camera_vertex.point;
+        let distance = to_light.norm();
+        let direction = to_light / distance;
+        // Rays don't find anything closer than the near clipping range, connections
+        // don't either so every strategy samples the same paths
+        if distance <= self.camera.near_clipping_range {
+            return none;
+        }
+
+        let light_scattering = if s == 1 {
+            light_vertex.emitted()
+        } else {
+            light_vertex.bsdf(&light_path[s - 2].point, &camera_vertex.point)
+        };
+
+        let (camera_scattering, splat) = if t == 1 {
+            // Like in `trace_path`, point lights aren't seen by the camera
+            if let VertexKind::PointLight(_) = light_vertex.kind {
+                return none;
+            }
+            match self.camera.project(&direction) {
+                Some((x, y, importance)) => {
+                    (Vector3::repeat(importance), Some(y * self.canvas_width + x))
+                }
+                None => return none,
+            }
+        } else {
+            (
+                camera_vertex.bsdf(&camera_path[t - 2].point, &light_vertex.point),
+                None,
+            )
+        };
+
+        let contribution = light_vertex
+            .throughput
+            .component_mul(&light_scattering)
+            .component_mul(&camera_scattering)
+            .component_mul(&camera_vertex.throughput)
+            * light_vertex.cos(&direction)
+            * camera_vertex.cos(&direction)
+            / (distance * distance);
+        if contribution == Vector3::zeros() {
+            return none;
+        }
+
+        let shadow_ray = Ray::new(camera_vertex.point, direction);
+        if self.occluded(
+            &shadow_ray,
+            self.camera.near_clipping_range,
+            distance - RAY_EPSILON,
+        ) {
+            return none;
+        }
+
+        (contribution, splat)
+    }
+
+    /// Weight of the strategy connecting `s` light vertices to `t` camera vertices,
+    /// against every other strategy which could have sampled the same path.
+    fn mis_weight(
+        &self,
+        settings: &RenderSettings,
+        light_path: &mut [Vertex],
+        camera_path: &mut [Vertex],
+        s: usize,
+        t: usize,
+    ) -> Real {
+        if s + t == 2 {
+            return 1.;
+        }
+
+        // Reverse densities at the connection depend on the strategy, they are
+        // set for the computation then restored
+        let camera_vertex = camera_path[t - 1];
+        let camera_previous = if t > 1 {
+            Some(camera_path[t - 2])
+        } else {
+            None
+        };
+        let light_vertex = if s > 0 { Some(light_path[s - 1]) } else { None };
+        let light_previous = if s > 1 { Some(light_path[s - 2]) } else { None };
+
+        let camera_vertex_reverse = match light_vertex {
+            Some(light_vertex) => {
+                self.vertex_pdf(&light_vertex, light_previous.as_ref(), &camera_vertex)
+            }
+            None => self.light_origin_pdf(&camera_vertex),
+        };
+        // Only the camera subpath can sample emitters which can't be picked as lights
+        if camera_vertex_reverse == 0. && s == 0 {
+            return 1.;
+        }
+        let camera_previous_reverse = camera_previous.map(|previous| match light_vertex {
+            Some(light_vertex) => self.vertex_pdf(&camera_vertex, Some(&light_vertex), &previous),
+            None => self.emission_pdf(&camera_vertex, &previous),
+        });
+        let (light_vertex_reverse, light_previous_reverse) = match light_vertex {
+            Some(light_vertex) => (
+                Some(self.vertex_pdf(&camera_vertex, camera_previous.as_ref(), &light_vertex)),
+                light_previous.map(|previous| {
+                    self.vertex_pdf(&light_vertex, Some(&camera_vertex), &previous)
+                }),
+            ),
+            None => (None, None),
+        };
+
+        camera_path[t - 1].pdf_reverse = camera_vertex_reverse;
+        camera_path[t - 1].delta = false;
+        if let Some(pdf) = camera_previous_reverse {
+            camera_path[t - 2].pdf_reverse = pdf;
+        }
+        if let Some(pdf) = light_vertex_reverse {
+            light_path[s - 1].pdf_reverse = pdf;
+            light_path[s - 1].delta = false;
+        }
+        if let Some(pdf) = light_previous_reverse {
+            light_path[s - 2].pdf_reverse = pdf;
+        }
+
+        // Densities of specular bounces are zero, they cancel out in the ratios
+        let remap = |pdf: Real| if pdf != 0. { pdf } else { 1. };
+        let mut weights = 0.;
+
+        // Strategies with fewer camera vertices
+        let mut ratio = 1.;
+        for i in (1..t).rev() {
+            ratio *= remap(camera_path[i].pdf_reverse) / remap(camera_path[i].pdf_forward);
+            if !camera_path[i].delta && !camera_path[i - 1].delta {
+                weights += settings.mis_heuristic.relative_weight(ratio);
+            }
+        }
+
+        // Strategies with fewer light vertices
+        let mut ratio = 1.;
+        for i in (0..s).rev() {
+            ratio *= remap(light_path[i].pdf_reverse) / remap(light_path[i].pdf_forward);
+            let delta_previous = match i {
+                0 => matches!(light_path[0].kind, VertexKind::PointLight(_)),
+                _ => light_path[i - 1].delta,
+            };
+            if !light_path[i].delta && !delta_previous {
+                weights += settings.mis_heuristic.relative_weight(ratio);
+            }
+        }
+
+        camera_path[t - 1] = camera_vertex;
+        if let Some(previous) = camera_previous {
+            camera_path[t - 2] = previous;
+        }
+        if let Some(light_vertex) = light_vertex {
+            light_path[s - 1] = light_vertex;
+        }
+        if let Some(previous) = light_previous {
+            light_path[s - 2] = previous;
+        }
+
+        1. / (1. + weights)
+    }
+}
@@ -0,0 +1,542 @@
+extern crate nalgebra;
+
+use nalgebra::Vector3;
+
+use std::io::{self, Read, Write};
+
+use crate::cache::{read_u64, read_vector, write_u64, write_vector};
+use crate::precision::Real;
+use crate::ray::Ray;
+
+// Number of buckets used to approximate the surface area heuristic.
+const SAH_BINS: usize = 12;
+// Cost of visiting a node relative to a primitive intersection.
+const TRAVERSAL_COST: Real = 0.125;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Aabb {
+    pub min: Vector3<Real>,
+    pub max: Vector3<Real>,
+}
+
+impl Aabb {
+    pub fn new(min: Vector3<Real>, max: Vector3<Real>) -> Self {
+        Self { min, max }
+    }
+
+    pub fn empty() -> Self {
+        Self {
+            min: Vector3::repeat(Real::INFINITY),
+            max: Vector3::repeat(Real::NEG_INFINITY),
+        }
+    }
+
+    pub fn from_points(points: &[Vector3<Real>]) -> Self {
+        points
+            .iter()
+            .fold(Aabb::empty(), |bounds, point| bounds.grow(point))
+    }
+
+    pub fn grow(&self, point: &Vector3<Real>) -> Self {
+        Self {
+            min: self.min.inf(point),
+            max: self.max.sup(point),
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        Self {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    pub fn centroid(&self) -> Vector3<Real> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> Real {
+        let d = self.max - self.min;
+        if d.x < 0. || d.y < 0. || d.z < 0. {
+            return 0.;
+        }
+        2. * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Slab test, returns the distance at which the ray enters the box.
+    pub fn intersects(
+        &self,
+        ray: &Ray,
+        inv_direction: &Vector3<Real>,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
+    ) -> Option<Real> {
+        self.range(ray, inv_direction, near_clipping_range, far_clipping_range)
+            .map(|(tmin, _)| tmin)
+    }
+
+    /// Slab test, returns the distances at which the ray enters and leaves the box.
+    pub fn range(
+        &self,
+        ray: &Ray,
+        inv_direction: &Vector3<Real>,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
+    ) -> Option<(Real, Real)> {
+        let mut tmin = near_clipping_range;
+        let mut tmax = far_clipping_range;
+
+        for axis in 0..3 {
+            let t0 = (self.min[axis] - ray.origin[axis]) * inv_direction[axis];
+            let t1 = (self.max[axis] - ray.origin[axis]) * inv_direction[axis];
+            // min/max discard the NaN produced when the origin lies on a slab
+            // of an axis the ray is parallel to.
+            tmin = tmin.max(t0.min(t1));
+            tmax = tmax.min(t0.max(t1));
+        }
+
+        if tmin > tmax {
+            return None;
+        }
+
+        Some((tmin, tmax))
+    }
+}
+
+struct BvhNode {
+    bounds: Aabb,
+    // Interior node: index of the second child (the first one directly follows).
+    // Leaf: index of the first primitive in `Bvh::indices`.
+    offset: usize,
+    // Zero for interior nodes.
+    count: usize,
+    axis: usize,
+}
+
+struct BuildItem {
+    index: usize,
+    bounds: Aabb,
+    centroid: Vector3<Real>,
+}
+
+/// Bounding volume hierarchy built with binned SAH over arbitrary primitives.
+/// Primitives are referred to by the index given at construction time.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    pub indices: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn new(primitives: Vec<(usize, Aabb)>, max_leaf_size: usize) -> Self {
+        let mut items = primitives
+            .into_iter()
+            .map(|(index, bounds)| BuildItem {
+                index,
+                bounds,
+                centroid: bounds.centroid(),
+            })
+            .collect::<Vec<BuildItem>>();
+
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(2 * items.len()),
+            indices: Vec::with_capacity(items.len()),
+        };
+
+        if !items.is_empty() {
+            bvh.build_recursive(&mut items, max_leaf_size.max(1));
+        }
+
+        bvh
+    }
+
+    fn build_recursive(&mut self, items: &mut [BuildItem], max_leaf_size: usize) -> usize {
+        let node_index = self.nodes.len();
+
+        let bounds = items
+            .iter()
+            .fold(Aabb::empty(), |acc, item| acc.union(&item.bounds));
+        let centroid_bounds = items
+            .iter()
+            .fold(Aabb::empty(), |acc, item| acc.grow(&item.centroid));
+
+        self.nodes.push(BvhNode {
+            bounds,
+            offset: self.indices.len(),
+            count: items.len(),
+            axis: 0,
+        });
+
+        if items.len() <= 1 {
+            self.indices.extend(items.iter().map(|item| item.index));
+            return node_index;
+        }
+
+        let mid = match Self::find_sah_split(items, &bounds, &centroid_bounds, max_leaf_size) {
+            Some((axis, split_bin)) => {
+                self.nodes[node_index].axis = axis;
+                Self::partition(items, |item| {
+                    Self::bin_index(item, axis, &centroid_bounds) < split_bin
+                })
+            }
+            None if items.len() <= max_leaf_size => {
+                self.indices.extend(items.iter().map(|item| item.index));
+                return node_index;
+            }
+            None => {
+                // Centroids are too close to be binned, fall back to a median split
+                let extent = centroid_bounds.max - centroid_bounds.min;
+                let axis = extent.imax();
+                self.nodes[node_index].axis = axis;
+                items.sort_by(|a, b| a.centroid[axis].total_cmp(&b.centroid[axis]));
+                items.len() / 2
+            }
+        };
+
+        let (left, right) = items.split_at_mut(mid);
+        self.build_recursive(left, max_leaf_size);
+        let right_index = self.build_recursive(right, max_leaf_size);
+
+        let node = &mut self.nodes[node_index];
+        node.offset = right_index;
+        node.count = 0;
+
+        node_index
+    }
+
+    fn bin_index(item: &BuildItem, axis: usize, centroid_bounds: &Aabb) -> usize {
+        let lo = centroid_bounds.min[axis];
+        let extent = centroid_bounds.max[axis] - lo;
+        let bin = (((item.centroid[axis] - lo) / extent) * SAH_BINS as Real) as usize;
+        bin.min(SAH_BINS - 1)
+    }
+
+    /// Returns the best (axis, first bin of the right side) split, or None if
+    /// making a leaf is cheaper.
+    fn find_sah_split(
+        items: &[BuildItem],
+        bounds: &Aabb,
+        centroid_bounds: &Aabb,
+        max_leaf_size: usize,
+    ) -> Option<(usize, usize)> {
+        let parent_area = bounds.surface_area();
+        let mut best: Option<(usize, usize)> = None;
+        let mut best_cost = if items.len() <= max_leaf_size {
+            items.len() as Real
+        } else {
+            Real::INFINITY
+        };
+
+        for axis in 0..3 {
+            if centroid_bounds.max[axis] - centroid_bounds.min[axis] <= 0. {
+                continue;
+            }
+
+            let mut bins = [(Aabb::empty(), 0usize); SAH_BINS];
+            for item in items {
+                let bin = &mut bins[Self::bin_index(item, axis, centroid_bounds)];
+                bin.0 = bin.0.union(&item.bounds);
+                bin.1 += 1;
+            }
+
+            // Sweep from the right to get the cost of every right partition
+            let mut right_areas = [0.; SAH_BINS];
+            let mut right_counts = [0; SAH_BINS];
+            let mut acc = (Aabb::empty(), 0);
+            for i in (1..SAH_BINS).rev() {
+                acc = (acc.0.union(&bins[i].0), acc.1 + bins[i].1);
+                right_areas[i] = acc.0.surface_area();
+                right_counts[i] = acc.1;
+            }
+
+            let mut acc = (Aabb::empty(), 0);
+            for i in 0..SAH_BINS - 1 {
+                acc = (acc.0.union(&bins[i].0), acc.1 + bins[i].1);
+                if acc.1 == 0 || right_counts[i + 1] == 0 {
+                    continue;
+                }
+
+                let cost = TRAVERSAL_COST
+                    + (acc.0.surface_area() * acc.1 as Real
+                        + right_areas[i + 1] * right_counts[i + 1] as Real)
+                        / parent_area.max(Real::MIN_POSITIVE);
+
+                if cost < best_cost {
+                    best_cost = cost;
+                    best = Some((axis, i + 1));
+                }
+            }
+        }
+
+        best
+    }
+
+    fn partition<F>(items: &mut [BuildItem], predicate: F) -> usize
+    where
+        F: Fn(&BuildItem) -> bool,
+    {
+        let mut mid = 0;
+        for i in 0..items.len() {
+            if predicate(&items[i]) {
+                items.swap(i, mid);
+                mid += 1;
+            }
+        }
+        mid
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        write_u64(writer, self.nodes.len() as u64)?;
+        for node in &self.nodes {
+            write_vector(writer, &node.bounds.min)?;
+            write_vector(writer, &node.bounds.max)?;
+            write_u64(writer, node.offset as u64)?;
+            write_u64(writer, node.count as u64)?;
+            write_u64(writer, node.axis as u64)?;
+        }
+
+        write_u64(writer, self.indices.len() as u64)?;
+        for index in &self.indices {
+            write_u64(writer, *index as u64)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a BVH written by `write`, checking that it can be traversed safely
+    /// and that no leaf holds more than `max_leaf_size` primitives.
+    pub fn read(reader: &mut impl Read, max_leaf_size: usize) -> io::Result<Self> {
+        let node_count = read_u64(reader)? as usize;
+        let mut nodes = Vec::new();
+        for _ in 0..node_count {
+            nodes.push(BvhNode {
+                bounds: Aabb::new(read_vector(reader)?, read_vector(reader)?),
+                offset: read_u64(reader)? as usize,
+                count: read_u64(reader)? as usize,
+                axis: read_u64(reader)? as usize,
+            });
+        }
+
+        let index_count = read_u64(reader)? as usize;
+        let mut indices = Vec::new();
+        for _ in 0..index_count {
+            indices.push(read_u64(reader)? as usize);
+        }
+
+        let valid = nodes.iter().enumerate().all(|(i, node)| {
+            node.axis < 3
+                && if node.count > 0 {
+                    node.count <= max_leaf_size
+                        && node
+                            .offset
+                            .checked_add(node.count)
+                            .is_some_and(|end| end <= indices.len())
+                } else {
+                    node.offset > i + 1 && node.offset < nodes.len()
+                }
+        });
+        if !valid {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupted BVH"));
+        }
+
+        Ok(Self { nodes, indices })
+    }
+
+    /// Visit leaves front to back, calling `intersect(primitive, far)` on every
+    /// primitive whose leaf can still contain a hit closer than `far`. The closure
+    /// returns the distance of a new closest hit, which shrinks the search range.
+    pub fn traverse<F>(
+        &self,
+        ray: &Ray,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
+        mut intersect: F,
+    ) where
+        F: FnMut(usize, Real) -> Option<Real>,
+    {
+        self.traverse_leaves(ray, near_clipping_range, far_clipping_range, |leaf, far| {
+            let mut min_t: Option<Real> = None;
+            for &primitive in leaf {
+                if let Some(t) = intersect(primitive, min_t.unwrap_or(far)) {
+                    min_t = Some(t);
+                }
+            }
+            min_t
+        });
+    }
+
+    /// Same as `traverse`, but hands over all the primitives of a leaf at once
+    /// so they can be tested together.
+    pub fn traverse_leaves<F>(
+        &self,
+        ray: &Ray,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
+        mut intersect: F,
+    ) where
+        F: FnMut(&[usize], Real) -> Option<Real>,
+    {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let inv_direction = ray.direction.map(|d| 1. / d);
+        let mut far = far_clipping_range;
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+
+            if node
+                .bounds
+                .intersects(ray, &inv_direction, near_clipping_range, far)
+                .is_none()
+            {
+                continue;
+            }
+
+            if node.count > 0 {
+                let leaf = &self.indices[node.offset..node.offset + node.count];
+                if let Some(t) = intersect(leaf, far) {
+                    far = far.min(t);
+                }
+                continue;
+            }
+
+            // Push the far child first so the near one is popped next
+            if ray.direction[node.axis] < 0. {
+                stack.push(node_index + 1);
+                stack.push(node.offset);
+            } else {
+                stack.push(node.offset);
+                stack.push(node_index + 1);
+            }
+        }
+    }
+
+    /// Whether `intersect(leaf)` returns true for any leaf the ray goes through
+    /// between the clipping ranges. Stops at the first one, in no particular order.
+    pub fn occluded_leaves<F>(
+        &self,
+        ray: &Ray,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
+        mut intersect: F,
+    ) -> bool
+    where
+        F: FnMut(&[usize]) -> bool,
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let inv_direction = ray.direction.map(|d| 1. / d);
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+
+            if node
+                .bounds
+                .intersects(ray, &inv_direction, near_clipping_range, far_clipping_range)
+                .is_none()
+            {
+                continue;
+            }
+
+            if node.count > 0 {
+                if intersect(&self.indices[node.offset..node.offset + node.count]) {
+                    return true;
+                }
+                continue;
+            }
+
+            stack.push(node.offset);
+            stack.push(node_index + 1);
+        }
+
+        false
+    }
+
+    /// Same as `occluded_leaves`, one primitive at a time.
+    pub fn occluded<F>(
+        &self,
+        ray: &Ray,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
+        mut intersect: F,
+    ) -> bool
+    where
+        F: FnMut(usize) -> bool,
+    {
+        self.occluded_leaves(ray, near_clipping_range, far_clipping_range, |leaf| {
+            leaf.iter().any(|&primitive| intersect(primitive))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::objects::{ObjectsTrait, Sphere};
+    use crate::rng::SampleRng;
+
+    fn random_vector(rng: &mut SampleRng, scale: Real) -> Vector3<Real> {
+        Vector3::new(rng.gen(), rng.gen(), rng.gen()).map(|x: Real| (x - 0.5) * scale)
+    }
+
+    #[test]
+    fn traversal_matches_brute_force() {
+        let mut rng = SampleRng::new(1, 0, 0, 0);
+        let spheres: Vec<Sphere> = (0..200)
+            .map(|_| Sphere {
+                center: random_vector(&mut rng, 20.),
+                radius: rng.gen::<Real>() * 0.8 + 0.1,
+                textmat: Default::default(),
+            })
+            .collect();
+        let bvh = Bvh::new(
+            spheres
+                .iter()
+                .enumerate()
+                .map(|(i, sphere)| (i, sphere.bounding_box().unwrap()))
+                .collect(),
+            1,
+        );
+
+        let mut hits = 0;
+        for _ in 0..1000 {
+            let ray = Ray::new(
+                random_vector(&mut rng, 30.),
+                random_vector(&mut rng, 2.).normalize(),
+            );
+            let (near, far) = (1e-4, 1e3);
+
+            let brute_force = spheres
+                .iter()
+                .filter_map(|sphere| sphere.intersects(&ray, near, far).map(|hit| hit.t))
+                .fold(None, |min: Option<Real>, t| {
+                    Some(min.map_or(t, |min| min.min(t)))
+                });
+
+            let mut nearest = None;
+            bvh.traverse(&ray, near, far, |i, far| {
+                let t = spheres[i].intersects(&ray, near, far)?.t;
+                nearest = Some(t);
+                Some(t)
+            });
+
+            assert_eq!(nearest, brute_force);
+            assert_eq!(
+                bvh.occluded(&ray, near, far, |i| spheres[i].occluded(&ray, near, far)),
+                brute_force.is_some()
+            );
+            hits += brute_force.is_some() as usize;
+        }
+        // Enough rays hit something for the comparison to mean anything
+        assert!(hits > 100);
+    }
+}
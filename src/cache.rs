@@ -0,0 +1,337 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{self, BufReader, BufWriter, Read, Write},
+    mem::size_of,
+    path::PathBuf,
+    time::UNIX_EPOCH,
+};
+
+use nalgebra::Vector3;
+
+use crate::{bvh::Bvh, mesh::MeshConfig, precision::Real, simd::LANES};
+
+const MAGIC: &[u8; 4] = b"PTMC";
+const VERSION: u32 = 1;
+// Bound for the path stored in the header, anything longer is a corrupted file
+const MAX_PATH_LENGTH: usize = 1 << 16;
+
+/// Geometry of a mesh once loaded and transformed, what the cache stores.
+pub struct MeshData {
+    pub vertices: Vec<Vector3<Real>>,
+    pub indices: Vec<[u32; 3]>,
+    pub bounds: [Vector3<Real>; 2],
+    pub bvh: Bvh,
+}
+
+/// Everything the cached data depends on. It is written at the start of the
+/// cache file and must match exactly for the cache to be used.
+#[derive(PartialEq)]
+struct CacheKey {
+    path: String,
+    mtime: (u64, u32),
+    transform: [Real; 7],
+    real_size: u8,
+}
+
+impl CacheKey {
+    fn new(config: &MeshConfig) -> io::Result<Self> {
+        let mtime = fs::metadata(&config.path)?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_err(io::Error::other)?;
+
+        let (origin, rotation) = (config.origin, config.rotation);
+
+        Ok(Self {
+            path: fs::canonicalize(&config.path)?
+                .to_string_lossy()
+                .into_owned(),
+            mtime: (mtime.as_secs(), mtime.subsec_nanos()),
+            transform: [
+                origin.x,
+                origin.y,
+                origin.z,
+                config.scale,
+                rotation.x,
+                rotation.y,
+                rotation.z,
+            ],
+            real_size: size_of::<Real>() as u8,
+        })
+    }
+
+    /// Cache file next to the obj, one per transform of the same model.
+    fn cache_path(&self, config: &MeshConfig) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        for value in &self.transform {
+            value.to_bits().hash(&mut hasher);
+        }
+        self.real_size.hash(&mut hasher);
+
+        PathBuf::from(format!(
+            "{}.{:016x}.meshcache",
+            config.path,
+            hasher.finish()
+        ))
+    }
+
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        write_u32(writer, VERSION)?;
+        writer.write_all(&[self.real_size])?;
+        write_u64(writer, self.path.len() as u64)?;
+        writer.write_all(self.path.as_bytes())?;
+        write_u64(writer, self.mtime.0)?;
+        write_u32(writer, self.mtime.1)?;
+        for value in &self.transform {
+            write_real(writer, *value)?;
+        }
+        Ok(())
+    }
+
+    /// None if the file was written by another version or precision.
+    fn read(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC || read_u32(reader)? != VERSION {
+            return Ok(None);
+        }
+
+        let mut real_size = [0; 1];
+        reader.read_exact(&mut real_size)?;
+        if real_size[0] as usize != size_of::<Real>() {
+            return Ok(None);
+        }
+
+        let path_length = read_u64(reader)? as usize;
+        if path_length > MAX_PATH_LENGTH {
+            return Ok(None);
+        }
+        let mut path = vec![0; path_length];
+        reader.read_exact(&mut path)?;
+        let mtime = (read_u64(reader)?, read_u32(reader)?);
+        let mut transform = [0.; 7];
+        for value in transform.iter_mut() {
+            *value = read_real(reader)?;
+        }
+
+        Ok(Some(Self {
+            path: String::from_utf8_lossy(&path).into_owned(),
+            mtime,
+            transform,
+            real_size: real_size[0],
+        }))
+    }
+}
+
+/// Load the cached geometry of a mesh, None if there is no cache or it is stale.
+pub fn load(config: &MeshConfig) -> Option<MeshData> {
+    let key = CacheKey::new(config).ok()?;
+    let mut reader = BufReader::new(File::open(key.cache_path(config)).ok()?);
+
+    if CacheKey::read(&mut reader).ok()?? != key {
+        return None;
+    }
+
+    read_mesh_data(&mut reader).ok()
+}
+
+pub fn save(config: &MeshConfig, data: &MeshData) -> io::Result<()> {
+    let key = CacheKey::new(config)?;
+    let path = key.cache_path(config);
+
+    // Write to a temporary file first so a killed process never leaves a truncated cache
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    key.write(&mut writer)?;
+    write_mesh_data(&mut writer, data)?;
+    writer.flush()?;
+    drop(writer);
+
+    fs::rename(tmp_path, path)
+}
+
+fn write_mesh_data(writer: &mut impl Write, data: &MeshData) -> io::Result<()> {
+    write_u64(writer, data.vertices.len() as u64)?;
+    for vertex in &data.vertices {
+        write_vector(writer, vertex)?;
+    }
+
+    write_u64(writer, data.indices.len() as u64)?;
+    for face in &data.indices {
+        for index in face {
+            write_u32(writer, *index)?;
+        }
+    }
+
+    write_vector(writer, &data.bounds[0])?;
+    write_vector(writer, &data.bounds[1])?;
+
+    data.bvh.write(writer)
+}
+
+fn read_mesh_data(reader: &mut impl Read) -> io::Result<MeshData> {
+    let vertex_count = read_u64(reader)? as usize;
+    let mut vertices = Vec::new();
+    for _ in 0..vertex_count {
+        vertices.push(read_vector(reader)?);
+    }
+
+    let face_count = read_u64(reader)? as usize;
+    let mut indices = Vec::new();
+    for _ in 0..face_count {
+        let face = [read_u32(reader)?, read_u32(reader)?, read_u32(reader)?];
+        if face.iter().any(|&index| index as usize >= vertex_count) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "vertex index out of range",
+            ));
+        }
+        indices.push(face);
+    }
+
+    let bounds = [read_vector(reader)?, read_vector(reader)?];
+    // Mesh leaves are gathered into a single SIMD packet
+    let bvh = Bvh::read(reader, LANES)?;
+    if bvh.indices.iter().any(|&index| index >= face_count) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "face index out of range",
+        ));
+    }
+
+    Ok(MeshData {
+        vertices,
+        indices,
+        bounds,
+        bvh,
+    })
+}
+
+pub fn write_u32(writer: &mut impl Write, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub fn write_u64(writer: &mut impl Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn write_real(writer: &mut impl Write, value: Real) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub fn read_real(reader: &mut impl Read) -> io::Result<Real> {
+    let mut bytes = [0; size_of::<Real>()];
+    reader.read_exact(&mut bytes)?;
+    Ok(Real::from_le_bytes(bytes))
+}
+
+pub fn write_vector(writer: &mut impl Write, vector: &Vector3<Real>) -> io::Result<()> {
+    for value in vector.iter() {
+        write_real(writer, *value)?;
+    }
+    Ok(())
+}
+
+pub fn read_vector(reader: &mut impl Read) -> io::Result<Vector3<Real>> {
+    Ok(Vector3::new(
+        read_real(reader)?,
+        read_real(reader)?,
+        read_real(reader)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::bvh::Aabb;
+    use crate::mesh::ObjData;
+    use crate::rng::SampleRng;
+
+    fn config() -> MeshConfig {
+        MeshConfig {
+            path: String::new(),
+            origin: Vector3::zeros(),
+            scale: 1.,
+            rotation: Vector3::zeros(),
+            textmat: Default::default(),
+        }
+    }
+
+    fn random_mesh_data(count: u32) -> MeshData {
+        let mut rng = SampleRng::new(5, 0, 0, 0);
+        config().triangularization(ObjData {
+            vertices: (0..3 * count)
+                .map(|_| Vector3::new(rng.gen(), rng.gen(), rng.gen()))
+                .collect(),
+            indices: (0..count).map(|i| [3 * i, 3 * i + 1, 3 * i + 2]).collect(),
+        })
+    }
+
+    fn to_bytes(data: &MeshData) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_mesh_data(&mut bytes, data).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn mesh_data_round_trip() {
+        let data = random_mesh_data(100);
+        let bytes = to_bytes(&data);
+        let read = read_mesh_data(&mut bytes.as_slice()).unwrap();
+
+        assert_eq!(read.vertices, data.vertices);
+        assert_eq!(read.indices, data.indices);
+        assert_eq!(read.bounds, data.bounds);
+        assert_eq!(to_bytes(&read), bytes);
+    }
+
+    #[test]
+    fn truncated_mesh_data_is_rejected() {
+        let bytes = to_bytes(&random_mesh_data(10));
+        for length in 0..bytes.len() {
+            assert!(read_mesh_data(&mut &bytes[..length]).is_err());
+        }
+    }
+
+    #[test]
+    fn oversized_leaves_are_rejected() {
+        // Identical triangles can't be split, they all end up in a single leaf
+        let count = 2 * LANES;
+        let vertices = vec![Vector3::zeros(), Vector3::x(), Vector3::y()];
+        let bvh = Bvh::new(
+            (0..count)
+                .map(|i| (i, Aabb::from_points(&vertices)))
+                .collect(),
+            count,
+        );
+        let data = MeshData {
+            vertices,
+            indices: vec![[0, 1, 2]; count],
+            bounds: [Vector3::zeros(), Vector3::new(1., 1., 0.)],
+            bvh,
+        };
+
+        let error = read_mesh_data(&mut to_bytes(&data).as_slice()).err();
+        assert_eq!(
+            error.map(|error| error.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+    }
+}
@@ -1,22 +1,22 @@
 extern crate nalgebra;
-use std::f64::{consts::PI, INFINITY};
 
-use nalgebra::{Vector3};
-use rand::Rng;
+use nalgebra::Vector3;
 use serde::Deserialize;
 
+use crate::precision::{consts::PI, Real};
 use crate::ray::Ray;
+use crate::sampler::{Sampler, CAMERA_DIMENSION};
 
-fn default_canvas_fov_x() -> f64 {
+fn default_canvas_fov_x() -> Real {
     return 130.0;
 }
 
-fn default_near_clipping_range() -> f64 {
+fn default_near_clipping_range() -> Real {
     return 0.5;
 }
 
-fn default_far_clipping_range() -> f64 {
-    return INFINITY;
+fn default_far_clipping_range() -> Real {
+    return Real::INFINITY;
 }
 
 fn default_canvas_width() -> u32 {
@@ -27,23 +27,23 @@ fn default_canvas_height() -> u32 {
     return 720;
 }
 
-fn default_camera_right() -> Vector3<f64> {
+fn default_camera_right() -> Vector3<Real> {
     return Vector3::new(1., 0., 0.);
 }
 
 #[derive(Copy, Clone, Debug, Deserialize)]
 pub struct Camera {
-    pub origin: Vector3<f64>,
-    pub up: Vector3<f64>,
-    pub forward: Vector3<f64>,
+    pub origin: Vector3<Real>,
+    pub up: Vector3<Real>,
+    pub forward: Vector3<Real>,
     #[serde(default = "default_camera_right")]
-    pub right: Vector3<f64>,
+    pub right: Vector3<Real>,
     #[serde(default = "default_canvas_fov_x")]
-    pub fov_x_deg: f64,
+    pub fov_x_deg: Real,
     #[serde(default = "default_near_clipping_range")]
-    pub near_clipping_range: f64,
+    pub near_clipping_range: Real,
     #[serde(default = "default_far_clipping_range")]
-    pub far_clipping_range: f64,
+    pub far_clipping_range: Real,
     #[serde(default = "default_canvas_width")]
     pub canvas_width: u32,
     #[serde(default = "default_canvas_height")]
@@ -51,28 +51,62 @@ pub struct Camera {
 }
 
 impl Camera {
-    pub fn create_ray(&self, x: usize, y: usize) -> Ray {
+    /// Direction through the center of the top left pixel, and the steps to the
+    /// next pixel on the right and below. The image plane is at `forward`.
+    fn viewport(&self) -> (Vector3<Real>, Vector3<Real>, Vector3<Real>) {
         let width = self.canvas_width;
         let height = self.canvas_height;
 
         let fov = self.fov_x_deg * PI / 180.;
 
         let viewport_width = (fov / 2.).tan();
-        let viewport_height = viewport_width * (height - 1) as f64 / (width - 1) as f64;
+        let viewport_height = viewport_width * (height - 1) as Real / (width - 1) as Real;
 
-        let step_x = ((2. * viewport_width) / (width - 1) as f64) * self.right;
-        let step_y = ((2. * viewport_height) / (height - 1) as f64) * -self.up;
+        let step_x = ((2. * viewport_width) / (width - 1) as Real) * self.right;
+        let step_y = ((2. * viewport_height) / (height - 1) as Real) * -self.up;
 
         let viewport_top_left =
             self.forward - viewport_width * self.right + viewport_height * self.up;
 
+        (viewport_top_left, step_x, step_y)
+    }
+
+    pub fn create_ray(&self, x: usize, y: usize, sampler: &mut dyn Sampler) -> Ray {
+        let (viewport_top_left, step_x, step_y) = self.viewport();
+
         // Add randomness for antialiasing
-        let mut rng = rand::thread_rng();
-        let dx = rng.gen::<f64>() - 0.5;
-        let dy = rng.gen::<f64>() - 0.5;
+        let (dx, dy) = sampler.get_2d(CAMERA_DIMENSION);
+        let (dx, dy) = (dx - 0.5, dy - 0.5);
 
-        let direction = viewport_top_left + step_x * (x as f64 + dx) + step_y * (y as f64 + dy);
+        let direction = viewport_top_left + step_x * (x as Real + dx) + step_y * (y as Real + dy);
 
         Ray::new(self.origin, direction.normalize())
     }
+
+    /// Pixel whose rays go in `direction`, a unit vector, with the density of
+    /// `create_ray` picking that direction when the pixel is itself picked
+    /// uniformly. None if the direction is outside the image.
+    pub fn project(&self, direction: &Vector3<Real>) -> Option<(usize, usize, Real)> {
+        let (viewport_top_left, step_x, step_y) = self.viewport();
+
+        let cos_theta = direction.dot(&self.forward);
+        if cos_theta <= 0. {
+            return None;
+        }
+
+        // Pixel centers are at integer coordinates of the image plane
+        let offset = direction / cos_theta - viewport_top_left;
+        let x = (offset.dot(&step_x) / step_x.norm_squared() + 0.5).floor();
+        let y = (offset.dot(&step_y) / step_y.norm_squared() + 0.5).floor();
+        if x < 0. || y < 0. || x >= self.canvas_width as Real || y >= self.canvas_height as Real {
+            return None;
+        }
+
+        // Uniform over the area of the image plane, converted to solid angle
+        let image_area =
+            self.canvas_width as Real * self.canvas_height as Real * step_x.norm() * step_y.norm();
+        let pdf = 1. / (image_area * cos_theta.powi(3));
+
+        Some((x as usize, y as usize, pdf))
+    }
 }
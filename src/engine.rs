@@ -1,22 +1,229 @@
-use std::f64::consts::PI;
+use std::collections::HashMap;
 use std::fs::File;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::Receiver;
-use std::sync::{mpsc, Arc};
+use std::sync::{mpsc, Arc, Barrier};
 use std::thread;
 
 use image::png::PNGEncoder;
 use image::ColorType;
 use nalgebra::{Rotation3, Vector3};
-use rand::Rng;
 
-use crate::objects::HitRecord;
+use crate::bidirectional::Bidirectional;
+use crate::bvh::Bvh;
+use crate::film::{Film, FilmPixel};
+use crate::medium::{FreeFlight, Media, Medium};
+use crate::metropolis::Metropolis;
+use crate::objects::{orthonormal_basis, HitRecord, Mesh};
+use crate::photon_mapping::{PhotonMap, VisiblePoint};
+use crate::precision::{consts::PI, Real, RAY_EPSILON};
+use crate::rng::SampleRng;
+use crate::sampler::{bounce_dimension, Sampler, SamplerKind};
 use crate::scene::Scene;
-use crate::texture_material::TextureMaterial;
+use crate::spectrum::{Wavelengths, REFERENCE_WAVELENGTH};
+use crate::texture_material::{Diffuse, Surface, TextureMaterial};
+use crate::volume::Volume;
 use crate::RenderMode;
 use crate::{camera::Camera, light::PointLight, objects::ObjectsTrait, Ray};
 
-const EPSILON: f64 = 1e-4;
+// Recursion depth of the raytracer
 const REFLECTION_DEPTH: u32 = 4;
+// Path length of the pathtracer when neither the scene nor the command line set it
+pub const DEFAULT_MIN_DEPTH: u32 = 3;
+pub const DEFAULT_MAX_DEPTH: u32 = 32;
+// Samples a pixel takes before its variance estimate is trusted
+const MIN_ADAPTIVE_SAMPLES: Real = 16.;
+// Noisiest pixels get at most this many times the samples of a pass
+const MAX_ADAPTIVE_FACTOR: Real = 4.;
+
+// Offsets of each random decision in the dimensions of a bounce
+pub const BSDF_DIMENSION: u32 = 0;
+pub const LIGHT_PICK_DIMENSION: u32 = 2;
+pub const LIGHT_DIMENSION: u32 = 3;
+pub const RUSSIAN_ROULETTE_DIMENSION: u32 = 5;
+pub const LOBE_DIMENSION: u32 = 6;
+pub const MEDIUM_DIMENSION: u32 = 7;
+pub const VOLUME_DIMENSION: u32 = 8;
+
+/// Progress notifications sent while rendering, the image itself is read
+/// from the shared `Film`.
+#[derive(Copy, Clone, Debug)]
+pub enum RenderEvent {
+    TileDone(usize),
+    PassDone(u32),
+    // Every pixel reached the adaptive sampling threshold, rendering stopped
+    Converged(u32),
+    // The work preceding the first pass is done
+    SetupDone,
+}
+
+/// How light and BSDF samples of the same light are weighted against each other.
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MisHeuristic {
+    Balance,
+    Power,
+}
+
+impl MisHeuristic {
+    /// Weight of a sample drawn with density `pdf` by one strategy, when the other
+    /// strategy would have drawn it with density `other_pdf`.
+    pub fn weight(self, pdf: Real, other_pdf: Real) -> Real {
+        match self {
+            MisHeuristic::Balance => pdf / (pdf + other_pdf),
+            MisHeuristic::Power => pdf * pdf / (pdf * pdf + other_pdf * other_pdf),
+        }
+    }
+
+    /// Share of another strategy in the weight of a sample, relative to the share
+    /// of the strategy used, given the ratio of their densities.
+    pub fn relative_weight(self, pdf_ratio: Real) -> Real {
+        match self {
+            MisHeuristic::Balance => pdf_ratio,
+            MisHeuristic::Power => pdf_ratio * pdf_ratio,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RenderSettings {
+    pub render_mode: RenderMode,
+    pub cpu: usize,
+    pub sample_per_iteration: u32,
+    // Relative error of the mean below which a pixel stops being sampled,
+    // None to sample every pixel uniformly forever
+    pub adaptive_threshold: Option<Real>,
+    pub seed: u64,
+    pub sampler: SamplerKind,
+    pub mis_heuristic: MisHeuristic,
+    // Bounces always traced, longer paths are ended by russian roulette
+    pub min_depth: u32,
+    // Bounces after which paths are cut
+    pub max_depth: u32,
+    // Photons shot by each pass of the photon mapping
+    pub photons_per_pass: usize,
+    // Gather radius of the photon mapping at the first pass
+    pub photon_radius: Real,
+    // Independent paths estimating the brightness of the image before the
+    // Metropolis chains start
+    pub bootstrap_samples: usize,
+    // Paths carry wavelengths instead of RGB channels
+    pub spectral: bool,
+    // Passes after which rendering stops, None to render until every pixel
+    // converged or the event receiver is dropped
+    pub max_passes: Option<u32>,
+}
+
+/// Work of a rendering mode during a pass. Workers render every tile of the pass,
+/// then every batch, and a single one ends the pass once they are all done.
+/// Before the first pass, they render the setup batches in the same way.
+pub trait Integrator: Send + Sync {
+    /// Number of batches rendered once before the first pass.
+    fn setup_batch_count(&self, _settings: &RenderSettings) -> usize {
+        0
+    }
+
+    /// Render setup batch `batch`.
+    fn render_setup_batch(
+        &self,
+        _engine: &Engine,
+        _settings: &RenderSettings,
+        _batch: usize,
+        _worker: &mut WorkerState,
+    ) {
+    }
+
+    /// Called by a single worker once the setup batches are done.
+    fn end_setup(&self, _engine: &Engine, _settings: &RenderSettings) {}
+
+    /// Render tile `tile_index` of the pass into the film, returns the number of
+    /// samples taken.
+    fn render_tile(
+        &self,
+        engine: &Engine,
+        settings: &RenderSettings,
+        film: &Film,
+        pass: u32,
+        tile_index: usize,
+        worker: &mut WorkerState,
+    ) -> usize;
+
+    /// Number of batches of the pass, rendered after its tiles.
+    fn batch_count(&self, _settings: &RenderSettings) -> usize {
+        0
+    }
+
+    /// Called by a single worker once the tiles are done, before the batches.
+    fn start_batches(&self) {}
+
+    /// Render batch `batch` of the pass.
+    fn render_batch(
+        &self,
+        _engine: &Engine,
+        _settings: &RenderSettings,
+        _pass: u32,
+        _batch: usize,
+        _worker: &mut WorkerState,
+    ) {
+    }
+
+    /// Called by a single worker once the pass is done.
+    fn end_pass(&self, _settings: &RenderSettings, _film: &Film, _pass: u32) {}
+}
+
+/// Sampler and buffers a worker reuses from tile to tile.
+pub struct WorkerState {
+    // Restarted on the random stream of every pixel sample
+    pub sampler: Box<dyn Sampler>,
+    pub pixels: Vec<FilmPixel>,
+    pub accumulated: Vec<FilmPixel>,
+    // Contributions landing on any pixel, with the pixel offset
+    pub splats: Vec<(usize, Vector3<Real>)>,
+    pub visible_points: Vec<VisiblePoint>,
+}
+
+impl WorkerState {
+    pub fn new(settings: &RenderSettings) -> Self {
+        Self {
+            sampler: settings.sampler.create(settings.seed),
+            pixels: Vec::new(),
+            accumulated: Vec::new(),
+            splats: Vec::new(),
+            visible_points: Vec::new(),
+        }
+    }
+}
+
+/// Raytracer and pathtracer, which sample every pixel independently.
+pub struct PixelIntegrator;
+
+impl Integrator for PixelIntegrator {
+    fn render_tile(
+        &self,
+        engine: &Engine,
+        settings: &RenderSettings,
+        film: &Film,
+        _pass: u32,
+        tile_index: usize,
+        worker: &mut WorkerState,
+    ) -> usize {
+        film.render_tile(
+            tile_index,
+            &mut worker.pixels,
+            &mut worker.accumulated,
+            |x, y, accumulated| {
+                let samples = Engine::pixel_samples(settings, accumulated);
+                engine.render_pixel(
+                    settings,
+                    x,
+                    y,
+                    accumulated.weight as u32,
+                    samples,
+                    worker.sampler.as_mut(),
+                )
+            },
+        )
+    }
+}
 
 pub struct Engine {
     pub camera: Camera,
@@ -24,6 +231,16 @@ pub struct Engine {
     pub lights: Vec<PointLight>,
     pub canvas_width: usize,
     pub canvas_height: usize,
+    // Acceleration structure over the bounded objects, see `build_bvh`
+    bvh: Bvh,
+    // Objects without bounding box, always tested
+    unbounded: Vec<usize>,
+    // Emissive objects sampled for direct lighting
+    pub emitters: Vec<usize>,
+    // Medium filling the scene around the objects, vacuum when None
+    pub medium: Option<Medium>,
+    // Heterogeneous volumes, crossed by rays on top of the medium
+    pub volumes: Vec<Volume>,
 }
 
 impl Engine {
@@ -34,6 +251,11 @@ impl Engine {
             lights: Vec::new(),
             canvas_width,
             canvas_height,
+            bvh: Bvh::new(vec![], 1),
+            unbounded: Vec::new(),
+            emitters: Vec::new(),
+            medium: None,
+            volumes: Vec::new(),
         }
     }
 
@@ -63,6 +285,27 @@ impl Engine {
             mesh.convert_to_triangles(&mut engine);
         }
 
+        // Load each instanced obj once, all its instances point to the same mesh
+        let models: HashMap<&str, TextureMaterial> = scene
+            .models
+            .iter()
+            .map(|model| (model.path.as_str(), model.textmat))
+            .collect();
+        let mut instanced_meshes: HashMap<&str, Arc<Mesh>> = HashMap::new();
+        for instance in &scene.instances {
+            let mesh = instanced_meshes
+                .entry(&instance.path)
+                .or_insert_with(|| {
+                    let textmat = models
+                        .get(instance.path.as_str())
+                        .copied()
+                        .unwrap_or_default();
+                    Arc::new(instance.mesh_config(textmat).load())
+                })
+                .clone();
+            engine.add_object(Box::new(instance.to_instance(mesh)));
+        }
+
         for plane in &scene.planes {
             engine.add_object(Box::new(plane.clone()));
         }
@@ -71,6 +314,10 @@ impl Engine {
             engine.add_light(light.clone());
         }
 
+        engine.medium = scene.medium;
+        engine.volumes = scene.volumes.iter().map(|volume| volume.load()).collect();
+        engine.build_bvh();
+
         return engine;
     }
 
@@ -82,17 +329,59 @@ impl Engine {
         self.lights.push(light)
     }
 
+    /// Build the BVH over all objects and list the ones sampled as lights. Must
+    /// be called again after `add_object` for the new objects to be visible.
+    pub fn build_bvh(&mut self) {
+        let mut bounded = vec![];
+        self.unbounded.clear();
+        self.emitters.clear();
+
+        for (i, object) in self.objects.iter().enumerate() {
+            match object.bounding_box() {
+                Some(bounds) => bounded.push((i, bounds)),
+                None => self.unbounded.push(i),
+            }
+
+            if Self::is_sampled_emitter(object.as_ref()) {
+                self.emitters.push(i);
+            }
+        }
+
+        self.bvh = Bvh::new(bounded, 1);
+    }
+
+    /// Whether direct lighting samples the object, see `emitters`.
+    pub fn is_sampled_emitter(object: &dyn ObjectsTrait) -> bool {
+        let emittance = object.get_texture().surface.emittance;
+        matches!(emittance, Some(e) if e.ke > 0.) && object.can_sample_surface()
+    }
+
+    /// Density with respect to solid angle of direct lighting picking the point
+    /// of `record` on `object`, seen from `origin`. Zero when no emitter is
+    /// sampled, only BSDF sampling then finds the lights.
+    fn emitter_pdf(
+        &self,
+        object: &dyn ObjectsTrait,
+        origin: &Vector3<Real>,
+        record: &HitRecord,
+    ) -> Real {
+        if self.emitters.is_empty() || !Self::is_sampled_emitter(object) {
+            return 0.;
+        }
+        object.surface_pdf(origin, record) / self.emitters.len() as Real
+    }
+
     pub fn buffer_float_to_u8(
-        float_buffer: &Vec<Vector3<f64>>,
+        float_buffer: &Vec<Vector3<Real>>,
         render_mode: RenderMode,
     ) -> Vec<u8> {
         let mut u8_buffer = vec![0; float_buffer.len() * 3];
 
         // Reduce gamma correction with raytracer
         let apply_gamma_corr = if render_mode == RenderMode::Raytracer {
-            |x: f64| (x.clamp(0., 1.).powf(1. / 1.5) * 255.) as u8
+            |x: Real| (x.clamp(0., 1.).powf(1. / 1.5) * 255.) as u8
         } else {
-            |x: f64| (x.clamp(0., 1.).sqrt() * 255.) as u8
+            |x: Real| (x.clamp(0., 1.).sqrt() * 255.) as u8
         };
 
         for (i, pixel) in float_buffer.iter().enumerate() {
@@ -104,94 +393,211 @@ impl Engine {
         return u8_buffer;
     }
 
-    pub fn stream_render(
-        self,
-        render_mode: RenderMode,
-        cpu: usize,
-        sample_per_iteration: u32,
-    ) -> Receiver<Vec<Vector3<f64>>> {
-        assert!((self.camera.canvas_width * self.camera.canvas_height) as usize % cpu == 0);
+    /// Render `samples` samples of a pixel, numbered from `first_sample` so each
+    /// one gets its own random stream of `sampler`.
+    pub fn render_pixel(
+        &self,
+        settings: &RenderSettings,
+        x: usize,
+        y: usize,
+        first_sample: u32,
+        samples: u32,
+        sampler: &mut dyn Sampler,
+    ) -> FilmPixel {
+        let mut pixel = FilmPixel::default();
+
+        if settings.render_mode == RenderMode::Raytracer {
+            if samples > 0 {
+                sampler.start_sample(x, y, first_sample);
+                let ray = self.camera.create_ray(x, y, sampler);
+                pixel.add_sample(self.trace_ray(
+                    &ray,
+                    REFLECTION_DEPTH,
+                    self.camera.near_clipping_range,
+                    self.camera.far_clipping_range,
+                ));
+            }
+            return pixel;
+        }
 
-        // Move engine to heap for rust-safe multithreading
-        let engine = Arc::new(self);
+        for sample in first_sample..first_sample + samples {
+            sampler.start_sample(x, y, sample);
+            let ray = self.camera.create_ray(x, y, sampler);
+            let wavelengths = Wavelengths::for_path(settings, sampler);
+            let radiance = self.trace_path(
+                settings,
+                &ray,
+                0,
+                Vector3::repeat(1.),
+                sampler,
+                None,
+                &wavelengths,
+            );
+            pixel.add_sample(wavelengths.to_rgb(&radiance));
+        }
 
-        // Setup stream
-        let (sender, receiver) = mpsc::channel();
+        pixel
+    }
 
-        // Spawn thread and loop indefinitely to return frame stream
-        thread::spawn(move || loop {
-            let mut handles = vec![];
-
-            for w in 0..cpu {
-                let engine = engine.clone();
-
-                let t = thread::spawn(move || {
-                    // Thread result buffer
-                    let mut thread_res = vec![
-                        Vector3::zeros();
-                        (engine.camera.canvas_width * engine.camera.canvas_height)
-                            as usize
-                            / cpu
-                    ];
-
-                    for y in 0..engine.canvas_height {
-                        for x in 0..engine.canvas_width {
-                            let offset = y * engine.canvas_width + x;
-
-                            // Only render pixels assigned to that thread
-                            if offset % cpu != w {
-                                continue;
-                            }
+    /// Number of samples to give a pixel this pass given what it accumulated so far.
+    /// With adaptive sampling, noisy pixels get more samples and converged ones none.
+    pub fn pixel_samples(settings: &RenderSettings, accumulated: &FilmPixel) -> u32 {
+        let threshold = match settings.adaptive_threshold {
+            Some(threshold) if accumulated.weight >= MIN_ADAPTIVE_SAMPLES => threshold,
+            _ => return settings.sample_per_iteration,
+        };
 
-                            if render_mode == RenderMode::Raytracer {
-                                let ray = engine.camera.create_ray(x, y);
+        let ratio = accumulated.relative_error() / threshold;
+        if ratio <= 1. {
+            return 0;
+        }
 
-                                thread_res[offset / cpu] = engine.trace_ray(
-                                    &ray,
-                                    REFLECTION_DEPTH,
-                                    engine.camera.near_clipping_range,
-                                    engine.camera.far_clipping_range,
-                                );
-                                continue;
-                            }
+        (settings.sample_per_iteration as Real * ratio.min(MAX_ADAPTIVE_FACTOR)).ceil() as u32
+    }
 
-                            let mut samples = vec![];
+    /// Work of the rendering mode of `settings`, see `Integrator`.
+    pub fn integrator(&self, settings: &RenderSettings, film: &Film) -> Box<dyn Integrator> {
+        match settings.render_mode {
+            RenderMode::Raytracer | RenderMode::Pathtracer => Box::new(PixelIntegrator),
+            RenderMode::Bidirectional => Box::new(Bidirectional),
+            // Photon mapping keeps statistics per pixel across passes
+            RenderMode::PhotonMapping => Box::new(PhotonMap::new(
+                self.canvas_width * self.canvas_height,
+                settings.photon_radius,
+                settings.photons_per_pass,
+            )),
+            // Metropolis runs one Markov chain per tile, started from the paths of
+            // a bootstrap done in the setup batches
+            RenderMode::Metropolis => Box::new(Metropolis::new(settings, film.tiles.len())),
+        }
+    }
 
-                            for _ in 0..sample_per_iteration {
-                                let ray = engine.camera.create_ray(x, y);
-                                samples.push(engine.trace_path(&ray, REFLECTION_DEPTH));
-                            }
+    /// Start `settings.cpu` workers rendering passes into the returned film, until
+    /// the event receiver is dropped, every pixel converged or `settings.max_passes`
+    /// passes are done. The image only depends on the settings, not on the number of
+    /// workers.
+    pub fn stream_render(self, settings: RenderSettings) -> (Arc<Film>, Receiver<RenderEvent>) {
+        let cpu = settings.cpu.max(1);
+        let film = Arc::new(Film::new(self.canvas_width, self.canvas_height));
+
+        // Move engine to heap for rust-safe multithreading
+        let engine = Arc::new(self);
 
-                            // Compute mean of samples
-                            let pixel = samples.iter().fold(Vector3::zeros(), |a, b| a + b)
-                                / samples.len() as f64;
+        // Setup stream
+        let (sender, receiver) = mpsc::channel();
+
+        // Workers pull tiles, then batches, from shared counters until none are
+        // left, so threads done with cheap ones keep helping with expensive ones.
+        // They are spawned once and meet at a barrier between passes.
+        let next_tile = Arc::new(AtomicUsize::new(0));
+        let next_batch = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(cpu));
+        let stop = Arc::new(AtomicBool::new(false));
+        let pass_samples = Arc::new(AtomicUsize::new(0));
+
+        let integrator: Arc<dyn Integrator> = Arc::from(engine.integrator(&settings, &film));
+
+        for _ in 0..cpu {
+            let engine = engine.clone();
+            let film = film.clone();
+            let sender = sender.clone();
+            let next_tile = next_tile.clone();
+            let next_batch = next_batch.clone();
+            let barrier = barrier.clone();
+            let stop = stop.clone();
+            let pass_samples = pass_samples.clone();
+            let integrator = integrator.clone();
+
+            thread::spawn(move || {
+                let mut worker = WorkerState::new(&settings);
+
+                let setup_batch_count = integrator.setup_batch_count(&settings);
+                if setup_batch_count > 0 {
+                    loop {
+                        let batch = next_batch.fetch_add(1, Ordering::Relaxed);
+                        if batch >= setup_batch_count {
+                            break;
+                        }
+                        integrator.render_setup_batch(&engine, &settings, batch, &mut worker);
+                    }
 
-                            thread_res[offset / cpu] = pixel;
+                    if barrier.wait().is_leader() {
+                        integrator.end_setup(&engine, &settings);
+                        if sender.send(RenderEvent::SetupDone).is_err() {
+                            stop.store(true, Ordering::Relaxed);
                         }
                     }
+                    barrier.wait();
+                }
 
-                    thread_res
-                });
+                for pass in 0.. {
+                    loop {
+                        let tile_index = next_tile.fetch_add(1, Ordering::Relaxed);
+                        if tile_index >= film.tiles.len() {
+                            break;
+                        }
 
-                // Store handle to join thread later
-                handles.push(t);
-            }
+                        let samples = integrator.render_tile(
+                            &engine,
+                            &settings,
+                            &film,
+                            pass,
+                            tile_index,
+                            &mut worker,
+                        );
+                        pass_samples.fetch_add(samples, Ordering::Relaxed);
+
+                        if sender.send(RenderEvent::TileDone(tile_index)).is_err() {
+                            // Receiver is gone, finish the pass and stop
+                            stop.store(true, Ordering::Relaxed);
+                        }
+                    }
+
+                    let batch_count = integrator.batch_count(&settings);
+                    if batch_count > 0 {
+                        if barrier.wait().is_leader() {
+                            integrator.start_batches();
+                            next_batch.store(0, Ordering::Relaxed);
+                        }
+                        barrier.wait();
 
-            let mut pixels = vec![Vector3::zeros(); engine.canvas_width * engine.canvas_height];
+                        loop {
+                            let batch = next_batch.fetch_add(1, Ordering::Relaxed);
+                            if batch >= batch_count {
+                                break;
+                            }
+                            integrator.render_batch(&engine, &settings, pass, batch, &mut worker);
+                        }
+                    }
 
-            // Wait for threads to finish and combine buffers to get a complete frame
-            for (i, handle) in handles.into_iter().enumerate() {
-                let thread_res = handle.join().unwrap();
+                    // Only one thread resets the queue once all the tiles are done
+                    if barrier.wait().is_leader() {
+                        film.end_pass();
+                        integrator.end_pass(&settings, &film, pass);
+                        next_tile.store(0, Ordering::Relaxed);
+                        let event = if pass_samples.swap(0, Ordering::Relaxed) == 0 {
+                            stop.store(true, Ordering::Relaxed);
+                            RenderEvent::Converged(pass)
+                        } else {
+                            if settings.max_passes == Some(pass + 1) {
+                                stop.store(true, Ordering::Relaxed);
+                            }
+                            RenderEvent::PassDone(pass)
+                        };
+                        if sender.send(event).is_err() {
+                            stop.store(true, Ordering::Relaxed);
+                        }
+                    }
+                    barrier.wait();
 
-                for (j, pixel) in thread_res.into_iter().enumerate() {
-                    pixels[j * cpu as usize + i] = pixel;
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
                 }
-            }
-
-            sender.send(pixels).unwrap();
-        });
+            });
+        }
 
-        return receiver;
+        return (film, receiver);
     }
 
     pub fn save(
@@ -209,30 +615,82 @@ impl Engine {
     pub fn get_closest_hit(
         &self,
         ray: &Ray,
-        near_clipping_range: f64,
-        far_clipping_range: f64,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
     ) -> Option<(HitRecord, &Box<dyn ObjectsTrait>)> {
         let mut min_t = far_clipping_range;
         let mut min_record = None;
 
         // Find the nearest object.
-        for object in &self.objects {
-            if let Some(record) = object.intersects(&ray, near_clipping_range, min_t) {
+        for &i in &self.unbounded {
+            let object = &self.objects[i];
+            if let Some(record) = object.intersects(ray, near_clipping_range, min_t) {
                 min_t = record.t;
                 min_record = Some((record, object));
             }
         }
 
+        self.bvh
+            .traverse(ray, near_clipping_range, min_t, |i, far_clipping_range| {
+                let object = &self.objects[i];
+                let record = object.intersects(ray, near_clipping_range, far_clipping_range)?;
+                let t = record.t;
+                min_record = Some((record, object));
+                Some(t)
+            });
+
         return min_record;
     }
 
-    fn sample_hemisphere(&self, normal: Vector3<f64>) -> (Vector3<f64>, f64) {
-        let sample = {
-            // Generate two floats with uniform distribution 0..1
-            let mut rng = rand::thread_rng();
-            let r1 = rng.gen::<f64>();
-            let r2 = rng.gen::<f64>();
+    /// Whether any object lies between the clipping ranges along the ray.
+    pub fn occluded(&self, ray: &Ray, near_clipping_range: Real, far_clipping_range: Real) -> bool {
+        self.unbounded
+            .iter()
+            .any(|&i| self.objects[i].occluded(ray, near_clipping_range, far_clipping_range))
+            || self
+                .bvh
+                .occluded(ray, near_clipping_range, far_clipping_range, |i| {
+                    self.objects[i].occluded(ray, near_clipping_range, far_clipping_range)
+                })
+    }
+
+    /// Fraction of light going through every surface between the clipping ranges,
+    /// the product of their transmission coefficients.
+    pub fn transmittance(
+        &self,
+        ray: &Ray,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
+    ) -> Real {
+        // Most shadow rays are either unoccluded or blocked by an opaque object,
+        // only walk the surfaces one by one when there is something to go through
+        if !self.occluded(ray, near_clipping_range, far_clipping_range) {
+            return 1.;
+        }
+
+        let mut transmittance = 1.;
+        let mut near_clipping_range = near_clipping_range;
+
+        while let Some((record, obj)) =
+            self.get_closest_hit(ray, near_clipping_range, far_clipping_range)
+        {
+            transmittance *= obj.get_texture().surface.transmission.kt;
+            if transmittance <= 0. {
+                return 0.;
+            }
+            near_clipping_range = record.t + RAY_EPSILON;
+        }
+
+        transmittance
+    }
 
+    /// Map `(r1, r2)`, uniform in [0, 1)^2, to a direction of the hemisphere.
+    pub fn sample_hemisphere(
+        &self,
+        normal: Vector3<Real>,
+        (r1, r2): (Real, Real),
+    ) -> (Vector3<Real>, Real) {
+        let sample = {
             // cos(theta) = u1 = y
             // cos^2(theta) + sin^2(theta) = 1 -> sin(theta) = srtf(1 - cos^2(theta))
             let sin_theta = (1. - r1 * r1).sqrt();
@@ -245,14 +703,7 @@ impl Engine {
 
         // Rotate sample direction to world coordinate
         let transform_matrix = {
-            let nx = if normal.x.is_normal() {
-                Vector3::new(normal.y, -normal.x, 0.).normalize()
-            } else {
-                Vector3::new(0., -normal.z, normal.y).normalize()
-            };
-
-            let nz = normal.cross(&nx);
-
+            let (nx, nz) = orthonormal_basis(&normal);
             Rotation3::from_basis_unchecked(&[nx, normal, nz])
         };
 
@@ -265,13 +716,14 @@ impl Engine {
     pub fn compute_refraction(
         &self,
         light_going_into: bool,
-        cos_theta: f64,
-        intersection_point: Vector3<f64>,
-        relative_normal: Vector3<f64>,
+        cos_theta: Real,
+        intersection_point: Vector3<Real>,
+        relative_normal: Vector3<Real>,
         ray: &Ray,
-    ) -> Option<(Ray, f64)> {
-        let (n_air, n_glass): (f64, f64) = (1., 1.5);
-        let n_ratio: f64 = if light_going_into {
+        n_glass: Real,
+    ) -> Option<(Ray, Real)> {
+        let n_air: Real = 1.;
+        let n_ratio: Real = if light_going_into {
             n_air / n_glass
         } else {
             n_glass / n_air
@@ -286,7 +738,7 @@ impl Engine {
         let cos_theta2 = cos_theta2_sqr.sqrt();
 
         let refracted_ray = Ray::new(
-            intersection_point - (relative_normal * EPSILON),
+            intersection_point - (relative_normal * RAY_EPSILON),
             ray.direction * n_ratio + relative_normal * (n_ratio * cos_theta - cos_theta2),
         );
 
@@ -307,94 +759,418 @@ impl Engine {
         Some((refracted_ray, fresnel))
     }
 
-    pub fn trace_path(&self, ray: &Ray, depth: u32) -> Vector3<f64> {
-        if depth == 0 {
+    /// Light reaching `point` from every point light, weighted like the diffuse
+    /// bounce of `trace_path` (cosine over 2 pi) and dimmed by `media` and the
+    /// transparent surfaces on the way. A point light can't be hit by a ray so
+    /// this connection is its only contribution.
+    pub fn point_lights_lightning(
+        &self,
+        point: Vector3<Real>,
+        normal: Vector3<Real>,
+        mut media: Option<&mut Media>,
+        wavelengths: &Wavelengths,
+    ) -> Vector3<Real> {
+        let origin = point + normal * RAY_EPSILON;
+        let mut lightning = Vector3::zeros();
+
+        for light in &self.lights {
+            let to_light = light.position - origin;
+            let distance = to_light.norm();
+            let direction = to_light / distance;
+            let cos_theta = normal.dot(&direction);
+            if cos_theta <= 0. {
+                continue;
+            }
+
+            let shadow_ray = Ray::new(origin, direction);
+            let transmittance =
+                self.transmittance(&shadow_ray, self.camera.near_clipping_range, distance);
+            if transmittance <= 0. {
+                continue;
+            }
+
+            // Intensity falls off with the squared distance
+            let incident =
+                light.intensity * transmittance * wavelengths.of(&light.color) * cos_theta
+                    / (2. * PI * distance * distance);
+            lightning += match media.as_deref_mut() {
+                Some(media) => {
+                    incident.component_mul(&self.media_transmittance(media, &shadow_ray, distance))
+                }
+                None => incident,
+            };
+        }
+
+        lightning
+    }
+
+    /// Light reaching `point` straight from one emitter picked at random, weighted
+    /// like the diffuse bounce of `trace_path` (cosine over 2 pi) and against the
+    /// chance of that bounce finding the same light. Without `diffuse`, nothing
+    /// else finds the light and the sample gets the whole weight. The light is
+    /// dimmed by `media` and the transparent surfaces on the way.
+    #[allow(clippy::too_many_arguments)]
+    pub fn sample_direct_lightning(
+        &self,
+        settings: &RenderSettings,
+        point: Vector3<Real>,
+        normal: Vector3<Real>,
+        diffuse: Option<&Diffuse>,
+        media: Option<&mut Media>,
+        wavelengths: &Wavelengths,
+        sampler: &mut dyn Sampler,
+        dimension: u32,
+    ) -> Vector3<Real> {
+        if self.emitters.is_empty() {
+            return Vector3::zeros();
+        }
+
+        let pick = sampler.get_1d(dimension + LIGHT_PICK_DIMENSION);
+        let emitter = &self.objects[self.emitters
+            [((pick * self.emitters.len() as Real) as usize).min(self.emitters.len() - 1)]];
+
+        let origin = point + normal * RAY_EPSILON;
+        let sample =
+            match emitter.sample_surface(&origin, sampler.get_2d(dimension + LIGHT_DIMENSION)) {
+                Some(sample) if sample.pdf > 0. => sample,
+                _ => return Vector3::zeros(),
+            };
+
+        let to_light = sample.point - origin;
+        let distance = to_light.norm();
+        let direction = to_light / distance;
+        let cos_theta = normal.dot(&direction);
+        if cos_theta <= 0. {
             return Vector3::zeros();
         }
 
-        match self.get_closest_hit(
-            &ray,
+        // Shadowed like the point lights, through the transparent surfaces
+        let shadow_ray = Ray::new(origin, direction);
+        let transmittance = self.transmittance(
+            &shadow_ray,
+            self.camera.near_clipping_range,
+            distance - RAY_EPSILON,
+        );
+        if transmittance <= 0. {
+            return Vector3::zeros();
+        }
+
+        let TextureMaterial { color, surface } = emitter.get_texture();
+        let emittance = wavelengths.of(&color) * surface.emittance.map(|e| e.ke).unwrap_or(0.);
+        // The emitter was picked with probability 1 / emitters
+        let pdf = sample.pdf / self.emitters.len() as Real;
+        let weight = match diffuse {
+            Some(diffuse) => settings
+                .mis_heuristic
+                .weight(pdf, diffuse.pdf(&normal, &direction)),
+            None => 1.,
+        };
+
+        let incident = emittance * transmittance * weight * cos_theta / (2. * PI * pdf);
+        match media {
+            Some(media) => {
+                incident.component_mul(&self.media_transmittance(media, &shadow_ray, distance))
+            }
+            None => incident,
+        }
+    }
+
+    /// Weight of the diffuse, mirror and refraction lobes in the light leaving a
+    /// surface hit by `ray`, with the refracted ray when there is one. Light is
+    /// refracted as if it had the given `wavelength`.
+    pub fn scattering_lobes(
+        &self,
+        surface: &Surface,
+        point: Vector3<Real>,
+        normal: Vector3<Real>,
+        ray: &Ray,
+        wavelength: Real,
+    ) -> ([Real; 3], Option<Ray>) {
+        let light_going_into = normal.dot(&ray.direction) < 0.;
+        let relative_normal = if light_going_into { normal } else { -normal };
+        let cos_theta = -relative_normal.dot(&ray.direction);
+
+        let mirror = if surface.reflection.kr > 0. { 1. } else { 0. };
+        if surface.transmission.kt > 0. {
+            match self.compute_refraction(
+                light_going_into,
+                cos_theta,
+                point,
+                relative_normal,
+                ray,
+                surface.transmission.ior(wavelength),
+            ) {
+                Some((refracted_ray, fresnel)) => (
+                    [
+                        surface.diffuse.kd,
+                        mirror * fresnel,
+                        (1. - fresnel) * surface.transmission.kt,
+                    ],
+                    Some(refracted_ray),
+                ),
+                // Handle total reflection
+                None => ([0., mirror, 0.], None),
+            }
+        } else {
+            // Don't use fresnel coefficient if surface is diffuse
+            (
+                [surface.diffuse.kd, mirror * surface.reflection.kr, 0.],
+                None,
+            )
+        }
+    }
+
+    /// Radiance coming back along `ray`, the `bounce`-th ray of its path.
+    /// `throughput` is the weight the result will be given in the pixel, used by
+    /// russian roulette. `bsdf_pdf` is the density of the diffuse bounce or of
+    /// the phase function which picked `ray`, None for camera and specular rays.
+    /// The previous vertex then also sampled the emitters directly, so their
+    /// emission is weighted against that strategy. The radiance is carried at
+    /// `wavelengths`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn trace_path(
+        &self,
+        settings: &RenderSettings,
+        ray: &Ray,
+        bounce: u32,
+        throughput: Vector3<Real>,
+        sampler: &mut dyn Sampler,
+        bsdf_pdf: Option<Real>,
+        wavelengths: &Wavelengths,
+    ) -> Vector3<Real> {
+        if bounce >= settings.max_depth {
+            return Vector3::zeros();
+        }
+
+        let dimension = bounce_dimension(bounce);
+
+        // Past the minimum depth, end paths with a probability growing as their
+        // weight falls, survivors are scaled up to keep the estimate unbiased
+        let survival = if bounce >= settings.min_depth {
+            throughput.max().min(1.)
+        } else {
+            1.
+        };
+        if survival < 1. && sampler.get_1d(dimension + RUSSIAN_ROULETTE_DIMENSION) >= survival {
+            return Vector3::zeros();
+        }
+        let throughput = throughput / survival;
+
+        let hit = self.get_closest_hit(
+            ray,
             self.camera.near_clipping_range,
             self.camera.far_clipping_range,
-        ) {
-            None => Vector3::<f64>::zeros(),
+        );
+
+        // The ray may be scattered by the media before reaching the surface
+        let mut media = Media {
+            medium: self
+                .segment_medium(
+                    ray,
+                    hit.as_ref().map(|(record, obj)| (record, obj.as_ref())),
+                )
+                .map(|medium| wavelengths.medium(&medium)),
+            rng: SampleRng::from_sample(sampler.get_1d(dimension + VOLUME_DIMENSION)),
+        };
+        let max_t = hit
+            .as_ref()
+            .map_or(self.camera.far_clipping_range, |(record, _)| record.t);
+        let u = sampler.get_1d(dimension + MEDIUM_DIMENSION);
+        let transmission = match self.sample_media(ray, max_t, u, &mut media, wavelengths) {
+            FreeFlight::Scattered {
+                t,
+                g,
+                weight,
+                emission,
+            } => {
+                let scattered = self.trace_medium_scattering(
+                    settings,
+                    g,
+                    ray.at(t),
+                    ray,
+                    bounce,
+                    throughput.component_mul(&weight),
+                    sampler,
+                    &mut media,
+                    wavelengths,
+                );
+                return (emission + weight.component_mul(&scattered)) / survival;
+            }
+            FreeFlight::Passed { weight } => weight,
+        };
+        let throughput = throughput.component_mul(&transmission);
+
+        let radiance = match hit {
+            None => Vector3::<Real>::zeros(),
             Some((record, obj)) => {
                 let TextureMaterial { color, surface } = obj.get_texture();
+                let color = wavelengths.of(&color);
                 let normal = record.normal;
                 let intersection_point = record.point;
 
-                let light_going_into = normal.dot(&ray.direction) < 0.;
-                let relative_normal = if light_going_into { normal } else { -normal };
-                let cos_theta = -relative_normal.dot(&ray.direction);
-
-                let emittance = color * surface.emittance.map(|e| e.ke).unwrap_or(0.);
-
-                let indirect_lightning = {
-                    let (wi, cos_theta2) = self.sample_hemisphere(relative_normal);
-                    let sample_ray = Ray::new(intersection_point + relative_normal * EPSILON, wi);
-                    let sample_color = cos_theta2 * self.trace_path(&sample_ray, depth - 1);
+                let relative_normal = if normal.dot(&ray.direction) < 0. {
+                    normal
+                } else {
+                    -normal
+                };
 
-                    surface.diffuse.kd * color.component_mul(&sample_color)
+                let (lobe_weights, refracted_ray) = self.scattering_lobes(
+                    &surface,
+                    intersection_point,
+                    normal,
+                    ray,
+                    wavelengths.hero(),
+                );
+                // Everything leaves through the mirror on a total reflection
+                let total_reflection = surface.transmission.kt > 0. && refracted_ray.is_none();
+
+                let emittance = match (surface.emittance, bsdf_pdf) {
+                    _ if total_reflection => Vector3::zeros(),
+                    (Some(emittance), Some(bsdf_pdf)) => {
+                        let light_pdf = self.emitter_pdf(obj.as_ref(), &ray.origin, &record);
+                        color * emittance.ke * settings.mis_heuristic.weight(bsdf_pdf, light_pdf)
+                    }
+                    (Some(emittance), None) => color * emittance.ke,
+                    (None, _) => Vector3::zeros(),
                 };
 
-                let reflection = if surface.reflection.kr > 0. {
-                    let reflected_ray = Ray::new(
-                        intersection_point + (relative_normal * EPSILON),
-                        (ray.direction
-                            - (2.0 * ray.direction.dot(&relative_normal) * relative_normal))
-                            .normalize(),
+                // Light entering a subsurface object leaves it elsewhere, the diffuse
+                // lobe isn't reflected at the hit point. Walks never hit the object
+                // from inside, whatever the orientation of its normals.
+                let subsurface = surface.subsurface;
+
+                // Like the emitters found by the bounce, which stops at the maximum depth
+                let direct_lightning = if lobe_weights[0] > 0.
+                    && subsurface.is_none()
+                    && bounce + 1 < settings.max_depth
+                {
+                    let incident = self.sample_direct_lightning(
+                        settings,
+                        intersection_point,
+                        relative_normal,
+                        Some(&surface.diffuse),
+                        Some(&mut media),
+                        wavelengths,
+                        sampler,
+                        dimension,
+                    ) + self.point_lights_lightning(
+                        intersection_point,
+                        relative_normal,
+                        Some(&mut media),
+                        wavelengths,
                     );
 
-                    color.component_mul(&self.trace_path(&reflected_ray, depth - 1))
+                    surface.diffuse.kd * color.component_mul(&incident)
                 } else {
                     Vector3::zeros()
                 };
 
-                let (refraction, fresnel) = if surface.transmission.kt > 0. {
-                    match self.compute_refraction(
-                        light_going_into,
-                        cos_theta,
+                // Continue the path into a single lobe, picked in proportion to its
+                // weight. Dividing by that probability leaves the total weight.
+                let total_weight: Real = lobe_weights.iter().sum();
+                if total_weight <= 0. {
+                    return transmission.component_mul(&(emittance + direct_lightning)) / survival;
+                }
+                let lobe = pick_lobe(
+                    &lobe_weights,
+                    total_weight,
+                    sampler.get_1d(dimension + LOBE_DIMENSION),
+                );
+
+                // The lobes of a dispersive surface were weighted and the refracted ray
+                // bent for the hero wavelength, the only one still followed after it
+                let (scattered_wavelengths, dispersion) =
+                    if surface.transmission.kt > 0. && surface.transmission.ior.is_some() {
+                        wavelengths.terminate_secondary()
+                    } else {
+                        (*wavelengths, Vector3::repeat(1.))
+                    };
+                let throughput = throughput.component_mul(&dispersion);
+
+                let scattered = match (lobe, refracted_ray, subsurface) {
+                    (0, _, Some(subsurface)) => color.component_mul(&self.trace_subsurface(
+                        settings,
+                        obj.as_ref(),
+                        &subsurface,
                         intersection_point,
                         relative_normal,
-                        ray,
-                    ) {
-                        Some((refracted_ray, fresnel)) => (
-                            surface.transmission.kt * self.trace_path(&refracted_ray, depth),
-                            fresnel,
-                        ),
-                        // Handle total reflection
-                        None => return reflection,
+                        bounce,
+                        throughput.component_mul(&color) * total_weight,
+                        sampler,
+                        &scattered_wavelengths,
+                    )),
+                    (0, _, None) => {
+                        let (wi, cos_theta2) = self.sample_hemisphere(
+                            relative_normal,
+                            sampler.get_2d(dimension + BSDF_DIMENSION),
+                        );
+                        let sample_ray =
+                            Ray::new(intersection_point + relative_normal * RAY_EPSILON, wi);
+                        let pdf = surface.diffuse.pdf(&relative_normal, &wi);
+                        let sample_color = cos_theta2
+                            * self.trace_path(
+                                settings,
+                                &sample_ray,
+                                bounce + 1,
+                                throughput.component_mul(&color) * total_weight * cos_theta2,
+                                sampler,
+                                Some(pdf),
+                                &scattered_wavelengths,
+                            );
+
+                        color.component_mul(&sample_color)
                     }
-                } else {
-                    (Vector3::zeros(), 0.)
+                    (1, _, _) => {
+                        let reflected_ray = Ray::new(
+                            intersection_point + (relative_normal * RAY_EPSILON),
+                            (ray.direction
+                                - (2.0 * ray.direction.dot(&relative_normal) * relative_normal))
+                                .normalize(),
+                        );
+
+                        color.component_mul(&self.trace_path(
+                            settings,
+                            &reflected_ray,
+                            bounce + 1,
+                            throughput.component_mul(&color) * total_weight,
+                            sampler,
+                            None,
+                            &scattered_wavelengths,
+                        ))
+                    }
+                    (_, Some(refracted_ray), _) => self.trace_path(
+                        settings,
+                        &refracted_ray,
+                        bounce + 1,
+                        throughput * total_weight,
+                        sampler,
+                        None,
+                        &scattered_wavelengths,
+                    ),
+                    // The refraction lobe has no weight without a refracted ray
+                    (_, None, _) => unreachable!(),
                 };
 
-                if surface.transmission.kt > 0. {
-                    emittance
-                        + indirect_lightning
-                        + fresnel * reflection
-                        + (1. - fresnel) * refraction
-                } else {
-                    // Don't use fresnel coefficient if surface is diffuse
-                    emittance + indirect_lightning + surface.reflection.kr * reflection + refraction
-                }
+                emittance + direct_lightning + total_weight * scattered.component_mul(&dispersion)
             }
-        }
+        };
+
+        transmission.component_mul(&radiance) / survival
     }
 
     pub fn trace_ray(
         &self,
         ray: &Ray,
         depth: u32,
-        near_clipping_range: f64,
-        far_clipping_range: f64,
-    ) -> Vector3<f64> {
+        near_clipping_range: Real,
+        far_clipping_range: Real,
+    ) -> Vector3<Real> {
         if depth == 0 {
             return Vector3::zeros();
         }
 
-        match self.get_closest_hit(&ray, near_clipping_range, far_clipping_range) {
-            None => Vector3::<f64>::zeros(),
+        match self.get_closest_hit(ray, near_clipping_range, far_clipping_range) {
+            None => Vector3::<Real>::zeros(),
             Some((record, obj)) => {
                 let TextureMaterial { color, surface } = obj.get_texture();
                 let intersection_point = record.point;
@@ -417,16 +1193,12 @@ impl Engine {
                     let light_distance = light_vec.norm();
                     let light_value = light.intensity * light.color;
 
-                    let shadow_ray =
-                        Ray::new(intersection_point, light_dir + relative_normal * EPSILON);
+                    let shadow_ray = Ray::new(
+                        intersection_point,
+                        light_dir + relative_normal * RAY_EPSILON,
+                    );
 
-                    let transmission = if let Some((_, obj)) =
-                        self.get_closest_hit(&shadow_ray, EPSILON, light_distance)
-                    {
-                        obj.get_texture().surface.transmission.kt
-                    } else {
-                        1.0
-                    };
+                    let transmission = self.transmittance(&shadow_ray, RAY_EPSILON, light_distance);
 
                     diffuse += transmission * {
                         let dot_prod = light_dir.dot(&relative_normal).clamp(0.0, 1.0);
@@ -444,7 +1216,7 @@ impl Engine {
 
                 let reflection = {
                     let reflected_ray = Ray::new(
-                        intersection_point + (relative_normal * EPSILON),
+                        intersection_point + (relative_normal * RAY_EPSILON),
                         reflected_dir,
                     );
                     self.trace_ray(
@@ -462,11 +1234,12 @@ impl Engine {
                         intersection_point,
                         relative_normal,
                         ray,
+                        surface.transmission.ior(REFERENCE_WAVELENGTH),
                     ) {
                         Some((refracted_ray, fresnel)) => (
                             self.trace_ray(
                                 &refracted_ray,
-                                depth,
+                                depth - 1,
                                 self.camera.near_clipping_range,
                                 self.camera.far_clipping_range,
                             ),
@@ -494,3 +1267,591 @@ impl Engine {
         }
     }
 }
+
+/// Index of the lobe picked by `sample`, uniform in [0, 1), each lobe being
+/// picked in proportion to its weight. `total_weight` must be positive.
+pub fn pick_lobe(lobe_weights: &[Real; 3], total_weight: Real, sample: Real) -> usize {
+    let mut pick = sample * total_weight;
+    // Rounding may leave `pick` past the last weight, fall back on the last lobe
+    // which can be picked
+    let mut lobe = lobe_weights.iter().rposition(|&w| w > 0.).unwrap();
+    for (i, &weight) in lobe_weights.iter().enumerate() {
+        if weight > 0. && pick < weight {
+            lobe = i;
+            break;
+        }
+        pick -= weight;
+    }
+    lobe
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::film::{luminance, TILE_SIZE};
+
+    /// YAML material with the given color and lobe weights.
+    pub fn material(color: &str, kd: Real, kr: Real, kt: Real, ke: Real) -> String {
+        format!(
+            "{{ color: {}, surface: {{ emittance: {{ ke: {} }}, diffuse: {{ kd: {} }}, \
+             specular: {{ ks: 0, ns: 1 }}, reflection: {{ kr: {} }}, \
+             transmission: {{ kt: {} }} }} }}",
+            color, ke, kd, kr, kt
+        )
+    }
+
+    /// Spheres given as YAML `(center, radius, material)`, seen by the camera of
+    /// `test_engine`.
+    pub fn spheres_engine(width: usize, height: usize, spheres: &[(&str, Real, String)]) -> Engine {
+        let spheres: Vec<String> = spheres
+            .iter()
+            .map(|(center, radius, textmat)| {
+                format!(
+                    "{{ center: {}, radius: {}, textmat: {} }}",
+                    center, radius, textmat
+                )
+            })
+            .collect();
+        let yaml = format!(
+            "camera:
+  origin: [0, 0, -3]
+  forward: [0, 0, 1]
+  up: [0, 1, 0]
+  fov_x_deg: 60
+  near_clipping_range: 0.01
+  canvas_width: {}
+  canvas_height: {}
+lights: []
+spheres: [{}]
+",
+            width,
+            height,
+            spheres.join(", ")
+        );
+
+        Engine::from_scene(&serde_yaml::from_str(&yaml).unwrap())
+    }
+
+    /// Small closed box lit by an emissive sphere and a point light, with a
+    /// diffuse, a mirror and a glass sphere inside.
+    pub fn test_engine(width: usize, height: usize) -> Engine {
+        let yaml = format!(
+            "camera:
+  origin: [0, 0, -3]
+  forward: [0, 0, 1]
+  up: [0, 1, 0]
+  fov_x_deg: 60
+  near_clipping_range: 0.01
+  canvas_width: {width}
+  canvas_height: {height}
+lights:
+  - {{ position: [0.5, 0.8, -1], intensity: 1, color: [1, 1, 1] }}
+spheres:
+  - {{ center: [0, 101, 0], radius: 100, textmat: {white} }}
+  - {{ center: [0, -101, 0], radius: 100, textmat: {white} }}
+  - {{ center: [101, 0, 0], radius: 100, textmat: {red} }}
+  - {{ center: [-101, 0, 0], radius: 100, textmat: {green} }}
+  - {{ center: [0, 0, 101], radius: 100, textmat: {white} }}
+  - {{ center: [0, 0, -104], radius: 100, textmat: {white} }}
+  - {{ center: [0, 0.9, 0], radius: 0.2, textmat: {light} }}
+  - {{ center: [-0.5, -0.7, 0.3], radius: 0.3, textmat: {white} }}
+  - {{ center: [0.1, -0.7, -0.2], radius: 0.3, textmat: {mirror} }}
+  - {{ center: [0.6, -0.6, 0.2], radius: 0.3, textmat: {glass} }}
+",
+            white = material("[0.8, 0.8, 0.8]", 0.9, 0., 0., 0.),
+            red = material("[0.8, 0.2, 0.2]", 0.9, 0., 0., 0.),
+            green = material("[0.2, 0.8, 0.2]", 0.9, 0., 0., 0.),
+            light = material("[1, 1, 1]", 0., 0., 0., 5.),
+            mirror = material("[1, 1, 1]", 0., 1., 0., 0.),
+            glass = material("[1, 1, 1]", 0., 1., 1., 0.),
+        );
+
+        Engine::from_scene(&serde_yaml::from_str(&yaml).unwrap())
+    }
+
+    pub fn test_settings(render_mode: RenderMode, cpu: usize) -> RenderSettings {
+        RenderSettings {
+            render_mode,
+            cpu,
+            sample_per_iteration: 2,
+            adaptive_threshold: None,
+            seed: 7,
+            sampler: SamplerKind::Independent,
+            mis_heuristic: MisHeuristic::Power,
+            min_depth: DEFAULT_MIN_DEPTH,
+            max_depth: 8,
+            photons_per_pass: 1000,
+            photon_radius: 0.1,
+            bootstrap_samples: 1000,
+            spectral: false,
+            max_passes: None,
+        }
+    }
+
+    #[test]
+    fn point_lights_are_dimmed_by_transparent_occluders() {
+        // Light straight above the origin, with a sphere of transmission `kt` in between
+        let lightning = |kt: Option<Real>| {
+            let sphere = kt.map_or(String::new(), |kt| {
+                format!(
+                    "{{ center: [0, 0, 2], radius: 0.5, textmat: {{ color: [1, 1, 1], \
+                     surface: {{ diffuse: {{ kd: 0 }}, specular: {{ ks: 0, ns: 1 }}, \
+                     reflection: {{ kr: 0 }}, transmission: {{ kt: {} }} }} }} }}",
+                    kt
+                )
+            });
+            let yaml = format!(
+                "camera:
+  origin: [0, 0, -3]
+  forward: [0, 0, 1]
+  up: [0, 1, 0]
+  fov_x_deg: 60
+  near_clipping_range: 0.01
+  canvas_width: 4
+  canvas_height: 4
+lights:
+  - {{ position: [0, 0, 4], intensity: 1, color: [1, 1, 1] }}
+spheres: [{}]
+",
+                sphere
+            );
+            let engine = Engine::from_scene(&serde_yaml::from_str(&yaml).unwrap());
+            engine.point_lights_lightning(Vector3::zeros(), Vector3::z(), None, &Wavelengths::Rgb)
+        };
+
+        let unoccluded = lightning(None);
+        assert!(unoccluded.x > 0.);
+        assert_eq!(lightning(Some(0.)), Vector3::zeros());
+        // Going through the sphere crosses two surfaces
+        let dimmed = lightning(Some(0.5));
+        assert!((dimmed - unoccluded * 0.25).norm() < 1e-6 * unoccluded.norm());
+    }
+
+    #[test]
+    fn area_lights_are_dimmed_like_point_lights() {
+        // Emissive sphere straight above the origin, with a sphere of transmission
+        // `kt` in between
+        let lightning = |kt: Option<Real>| {
+            let mut spheres = vec![("[0, 0, 4]", 0.2, material("[1, 1, 1]", 0., 0., 0., 1.))];
+            if let Some(kt) = kt {
+                spheres.push(("[0, 0, 2]", 0.5, material("[1, 1, 1]", 0., 0., kt, 0.)));
+            }
+            let engine = spheres_engine(4, 4, &spheres);
+            let settings = test_settings(RenderMode::Pathtracer, 1);
+            let mut sampler = settings.sampler.create(settings.seed);
+
+            (0..100)
+                .map(|index| {
+                    sampler.start_sample(0, 0, index);
+                    engine.sample_direct_lightning(
+                        &settings,
+                        Vector3::zeros(),
+                        Vector3::z(),
+                        None,
+                        None,
+                        &Wavelengths::Rgb,
+                        sampler.as_mut(),
+                        bounce_dimension(0),
+                    )
+                })
+                .sum::<Vector3<Real>>()
+        };
+
+        let unoccluded = lightning(None);
+        assert!(unoccluded.x > 0.);
+        assert_eq!(lightning(Some(0.)), Vector3::zeros());
+        // Going through the sphere crosses two surfaces
+        let dimmed = lightning(Some(0.5));
+        assert!((dimmed - unoccluded * 0.25).norm() < 1e-6 * unoccluded.norm());
+    }
+
+    #[test]
+    fn shadow_rays_go_through_transparent_panes() {
+        // Square panes of transmission `kt` across the z axis at the given depths,
+        // as two triangles each
+        let panes_engine = |panes: &[(Real, Real)]| {
+            let triangles: Vec<String> = panes
+                .iter()
+                .flat_map(|&(z, kt)| {
+                    let textmat = material("[1, 1, 1]", 1. - kt, 0., kt, 0.);
+                    [
+                        format!(
+                            "{{ v0: [-1, -1, {z}], v1: [1, -1, {z}], v2: [1, 1, {z}], textmat: {} }}",
+                            textmat
+                        ),
+                        format!(
+                            "{{ v0: [-1, -1, {z}], v1: [1, 1, {z}], v2: [-1, 1, {z}], textmat: {} }}",
+                            textmat
+                        ),
+                    ]
+                })
+                .collect();
+            let yaml = format!(
+                "camera:
+  origin: [0, 0, -3]
+  forward: [0, 0, 1]
+  up: [0, 1, 0]
+  fov_x_deg: 60
+  near_clipping_range: 0.01
+  canvas_width: 4
+  canvas_height: 4
+lights: []
+triangles: [{}]
+",
+                triangles.join(", ")
+            );
+            Engine::from_scene(&serde_yaml::from_str(&yaml).unwrap())
+        };
+        let ray = Ray::new(Vector3::new(0.1, 0.2, 0.), Vector3::z());
+
+        let clear = panes_engine(&[]);
+        assert!(!clear.occluded(&ray, RAY_EPSILON, 10.));
+        assert_eq!(clear.transmittance(&ray, RAY_EPSILON, 10.), 1.);
+
+        let opaque = panes_engine(&[(1., 0.)]);
+        assert!(opaque.occluded(&ray, RAY_EPSILON, 10.));
+        assert_eq!(opaque.transmittance(&ray, RAY_EPSILON, 10.), 0.);
+        // Blockers beyond the far clipping range are ignored
+        assert!(!opaque.occluded(&ray, RAY_EPSILON, 0.5));
+
+        let kt = 0.6;
+        let glass = panes_engine(&[(1., kt), (2., kt)]);
+        assert!(glass.occluded(&ray, RAY_EPSILON, 10.));
+        let transmittance = glass.transmittance(&ray, RAY_EPSILON, 10.);
+        assert!((transmittance - kt * kt).abs() < 1e-6, "{}", transmittance);
+        assert!((glass.transmittance(&ray, RAY_EPSILON, 1.5) - kt).abs() < 1e-6);
+    }
+
+    /// Image after `passes` passes, as resolved for the viewer.
+    pub fn render(engine: Engine, settings: RenderSettings, passes: u32) -> Vec<Vector3<Real>> {
+        render_film(engine, settings, passes).1
+    }
+
+    /// Same as `render`, along with the film.
+    pub fn render_film(
+        engine: Engine,
+        settings: RenderSettings,
+        passes: u32,
+    ) -> (Arc<Film>, Vec<Vector3<Real>>) {
+        let (width, height) = (engine.canvas_width, engine.canvas_height);
+        let (film, receiver) = engine.stream_render(RenderSettings {
+            max_passes: Some(passes),
+            ..settings
+        });
+        // Follow the events like the viewer does, workers drop their senders once
+        // the last pass is done
+        let mut image = vec![Vector3::zeros(); width * height];
+        for event in receiver.iter() {
+            film.update(&event, &mut image);
+        }
+        (film, image)
+    }
+
+    #[test]
+    fn render_is_independent_of_thread_count() {
+        for render_mode in [
+            RenderMode::Raytracer,
+            RenderMode::Pathtracer,
+            RenderMode::Bidirectional,
+            RenderMode::PhotonMapping,
+            RenderMode::Metropolis,
+        ] {
+            // Four tiles and several photon and bootstrap batches, with adaptive
+            // sampling
+            let settings = RenderSettings {
+                adaptive_threshold: Some(0.5),
+                photons_per_pass: 5000,
+                bootstrap_samples: 10_000,
+                ..test_settings(render_mode, 1)
+            };
+            let single = render(test_engine(40, 33), settings, 2);
+            let multi = render(
+                test_engine(40, 33),
+                RenderSettings { cpu: 4, ..settings },
+                2,
+            );
+
+            let bits = |image: &[Vector3<Real>]| {
+                image
+                    .iter()
+                    .flat_map(|pixel| pixel.iter().map(|x| x.to_bits()))
+                    .collect::<Vec<_>>()
+            };
+            assert!(single.iter().any(|pixel| pixel != &Vector3::zeros()));
+            assert!(bits(&single) == bits(&multi), "{:?}", render_mode);
+        }
+    }
+
+    #[test]
+    fn pass_renders_every_tile_once() {
+        // Not a multiple of the tile size, edge tiles are cropped
+        let engine = test_engine(TILE_SIZE * 3 + 5, TILE_SIZE + 1);
+        let (film, receiver) = engine.stream_render(test_settings(RenderMode::Pathtracer, 4));
+
+        let mut rendered = vec![0; film.tiles.len()];
+        for event in receiver.iter() {
+            match event {
+                RenderEvent::TileDone(tile) => rendered[tile] += 1,
+                _ => break,
+            }
+        }
+        assert!(rendered.iter().all(|&count| count == 1));
+
+        // Tiles cover every pixel exactly once
+        let mut covered = vec![0; film.width * film.height];
+        for tile in &film.tiles {
+            for y in tile.y..tile.y + tile.height {
+                for x in tile.x..tile.x + tile.width {
+                    covered[y * film.width + x] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|&count| count == 1));
+    }
+
+    #[test]
+    fn single_pass_shows_light_path_contributions() {
+        for render_mode in [
+            RenderMode::Bidirectional,
+            RenderMode::PhotonMapping,
+            RenderMode::Metropolis,
+        ] {
+            let settings = test_settings(render_mode, 2);
+            let image = render(test_engine(24, 16), settings, 1);
+
+            // Same render, only resolving the tiles as they are reported
+            let (film, receiver) = test_engine(24, 16).stream_render(RenderSettings {
+                max_passes: Some(1),
+                ..settings
+            });
+            let mut tiles_only = vec![Vector3::zeros(); image.len()];
+            for event in receiver.iter() {
+                if let RenderEvent::TileDone(tile) = event {
+                    film.resolve_tile(tile, &mut tiles_only);
+                }
+            }
+
+            // Splats and photons of the pass only land once it ends
+            let brighter = image
+                .iter()
+                .zip(&tiles_only)
+                .filter(|(pixel, tile_pixel)| luminance(pixel) > luminance(tile_pixel))
+                .count();
+            assert!(brighter > 0, "{:?}", render_mode);
+        }
+    }
+
+    /// Samples taken by every pixel, row by row.
+    fn sample_counts(film: &Film) -> Vec<Real> {
+        let mut counts = vec![0.; film.width * film.height];
+        let mut pixels = vec![];
+        for (tile_index, tile) in film.tiles.iter().enumerate() {
+            film.read_tile(tile_index, &mut pixels);
+            for (i, pixel) in pixels.iter().enumerate() {
+                counts[(tile.y + i / tile.width) * film.width + tile.x + i % tile.width] =
+                    pixel.weight;
+            }
+        }
+        counts
+    }
+
+    /// Emissive sphere on the left, seen directly, and a diffuse sphere on the
+    /// right only lit by it.
+    fn flat_and_noisy_engine() -> Engine {
+        spheres_engine(
+            24,
+            16,
+            &[
+                ("[-1, 0, 0]", 0.6, material("[1, 1, 1]", 0., 0., 0., 4.)),
+                (
+                    "[1, 0, 0]",
+                    0.6,
+                    material("[0.8, 0.8, 0.8]", 0.9, 0., 0., 0.),
+                ),
+            ],
+        )
+    }
+
+    #[test]
+    fn adaptive_sampling_stops_on_converged_pixels() {
+        let settings = RenderSettings {
+            adaptive_threshold: Some(0.02),
+            sample_per_iteration: 4,
+            ..test_settings(RenderMode::Pathtracer, 2)
+        };
+        let (film, _) = render_film(flat_and_noisy_engine(), settings, 6);
+        let (longer, _) = render_film(flat_and_noisy_engine(), settings, 12);
+        let (counts, longer_counts) = (sample_counts(&film), sample_counts(&longer));
+
+        // Inside the emissive sphere, in the background and on the diffuse sphere
+        let (emitter, background, diffuse) = (7 * 24 + 4, 24 + 12, 7 * 24 + 17);
+        for flat in [emitter, background] {
+            assert_eq!(counts[flat], MIN_ADAPTIVE_SAMPLES);
+            assert_eq!(longer_counts[flat], MIN_ADAPTIVE_SAMPLES);
+        }
+        assert!(counts[diffuse] > MIN_ADAPTIVE_SAMPLES);
+        assert!(longer_counts[diffuse] > counts[diffuse]);
+    }
+
+    #[test]
+    fn heatmap_has_image_size() {
+        let settings = RenderSettings {
+            adaptive_threshold: Some(0.02),
+            ..test_settings(RenderMode::Pathtracer, 2)
+        };
+        let (film, _) = render_film(flat_and_noisy_engine(), settings, 2);
+
+        // One RGB pixel per pixel of the image
+        assert_eq!(film.sample_heatmap().len(), 24 * 16 * 3);
+    }
+
+    /// Mean luminance of the pixels of `image`.
+    fn mean_luminance(image: &[Vector3<Real>]) -> Real {
+        image.iter().map(luminance).sum::<Real>() / image.len() as Real
+    }
+
+    /// Diffuse sphere under a smaller emissive one.
+    fn lit_sphere_engine() -> Engine {
+        spheres_engine(
+            16,
+            12,
+            &[
+                ("[0, 1.2, 0.5]", 0.5, material("[1, 1, 1]", 0., 0., 0., 4.)),
+                (
+                    "[0, -0.3, 0]",
+                    0.8,
+                    material("[0.8, 0.6, 0.4]", 0.9, 0., 0., 0.),
+                ),
+            ],
+        )
+    }
+
+    #[test]
+    fn direct_lightning_is_unbiased() {
+        let settings = RenderSettings {
+            sample_per_iteration: 32,
+            ..test_settings(RenderMode::Pathtracer, 4)
+        };
+        let mean = |mis_heuristic, sample_emitters: bool| {
+            let mut engine = lit_sphere_engine();
+            if !sample_emitters {
+                engine.emitters.clear();
+            }
+            let settings = RenderSettings {
+                mis_heuristic,
+                ..settings
+            };
+            mean_luminance(&render(engine, settings, 8))
+        };
+
+        // Only BSDF sampling finds the light without emitters to sample
+        let bsdf = mean(MisHeuristic::Power, false);
+        let power = mean(MisHeuristic::Power, true);
+        let balance = mean(MisHeuristic::Balance, true);
+        assert!((power - bsdf).abs() < 0.02 * bsdf, "{} {}", power, bsdf);
+        assert!(
+            (balance - power).abs() < 0.02 * power,
+            "{} {}",
+            balance,
+            power
+        );
+    }
+
+    #[test]
+    fn russian_roulette_is_unbiased() {
+        let mean = |min_depth| {
+            let settings = RenderSettings {
+                sample_per_iteration: 32,
+                min_depth,
+                ..test_settings(RenderMode::Pathtracer, 4)
+            };
+            mean_luminance(&render(lit_sphere_engine(), settings, 8))
+        };
+
+        // Paths always reach the maximum depth when roulette never starts
+        let (roulette, full) = (mean(0), mean(100));
+        assert!(
+            (roulette - full).abs() < 0.02 * full,
+            "{} {}",
+            roulette,
+            full
+        );
+    }
+
+    #[test]
+    fn max_depth_caps_paths() {
+        let render_depth = |max_depth| {
+            let settings = RenderSettings {
+                max_depth,
+                ..test_settings(RenderMode::Pathtracer, 2)
+            };
+            render(lit_sphere_engine(), settings, 2)
+        };
+
+        // Camera rays only see the white light, not its reflection on the tinted
+        // sphere
+        let tinted = |image: &[Vector3<Real>]| {
+            image
+                .iter()
+                .filter(|pixel| pixel.x != pixel.y || pixel.y != pixel.z)
+                .count()
+        };
+        assert_eq!(tinted(&render_depth(1)), 0);
+        assert!(tinted(&render_depth(2)) > 0);
+    }
+
+    #[test]
+    fn lobes_are_picked_in_proportion_to_their_weight() {
+        for weights in [
+            [0.2, 0.5, 0.3],
+            [0.6, 0., 0.9],
+            [0., 0.4, 0.],
+            [0.9, 0.1, 0.],
+        ] {
+            let total: Real = weights.iter().sum();
+            let samples = 10_000;
+            let mut counts = [0; 3];
+            for i in 0..samples {
+                counts[pick_lobe(&weights, total, (i as Real + 0.5) / samples as Real)] += 1;
+            }
+
+            for (count, weight) in counts.iter().zip(weights) {
+                let frequency = *count as Real / samples as Real;
+                assert!((frequency - weight / total).abs() < 1e-3, "{:?}", weights);
+            }
+            // Rounding of the largest sample never picks a lobe without weight
+            let last = pick_lobe(&weights, total, 1. - Real::EPSILON / 2.);
+            assert!(weights[last] > 0.);
+        }
+    }
+
+    #[test]
+    fn picking_a_lobe_matches_evaluating_all_of_them() {
+        // Convex sphere, light never reaches it twice so its lobes add up
+        let mean = |kd: Real, kr: Real| {
+            let engine = spheres_engine(
+                16,
+                12,
+                &[
+                    ("[0, 3.2, -1]", 2., material("[1, 1, 1]", 0., 0., 0., 1.)),
+                    (
+                        "[0, -0.3, 0]",
+                        0.8,
+                        material("[0.8, 0.6, 0.4]", kd, kr, 0., 0.),
+                    ),
+                ],
+            );
+            let settings = RenderSettings {
+                sample_per_iteration: 64,
+                ..test_settings(RenderMode::Pathtracer, 4)
+            };
+            mean_luminance(&render(engine, settings, 8))
+        };
+
+        // The light is out of view
+        assert_eq!(mean(0., 0.), 0.);
+        let mixed = mean(0.5, 0.5);
+        let lobes = mean(0.5, 0.) + mean(0., 0.5);
+        assert!((mixed - lobes).abs() < 0.02 * lobes, "{} {}", mixed, lobes);
+    }
+}
@@ -0,0 +1,284 @@
+extern crate nalgebra;
+
+use std::sync::Mutex;
+
+use nalgebra::Vector3;
+
+use crate::engine::RenderEvent;
+use crate::precision::Real;
+
+pub const TILE_SIZE: usize = 32;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Tile {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// Luminance below which errors are measured in absolute rather than relative terms
+const MIN_LUMINANCE: Real = 1e-2;
+
+#[derive(Copy, Clone, Debug)]
+pub struct FilmPixel {
+    pub sum: Vector3<Real>,
+    // Sum of the squared luminance of every sample, to track the variance
+    pub sum_squares: Real,
+    pub weight: Real,
+}
+
+impl Default for FilmPixel {
+    fn default() -> Self {
+        Self {
+            sum: Vector3::zeros(),
+            sum_squares: 0.,
+            weight: 0.,
+        }
+    }
+}
+
+impl FilmPixel {
+    pub fn add_sample(&mut self, sample: Vector3<Real>) {
+        self.sum += sample;
+        self.sum_squares += luminance(&sample).powi(2);
+        self.weight += 1.;
+    }
+
+    pub fn value(&self) -> Vector3<Real> {
+        if self.weight == 0. {
+            return Vector3::zeros();
+        }
+        self.sum / self.weight
+    }
+
+    /// Standard error of the mean luminance, relative to that mean.
+    pub fn relative_error(&self) -> Real {
+        if self.weight < 2. {
+            return Real::INFINITY;
+        }
+
+        let mean = luminance(&self.value());
+        let variance = (self.sum_squares / self.weight - mean * mean).max(0.) * self.weight
+            / (self.weight - 1.);
+
+        (variance / self.weight).sqrt() / mean.max(MIN_LUMINANCE)
+    }
+}
+
+pub fn luminance(color: &Vector3<Real>) -> Real {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+/// Contribution to the pixel at an offset of the image.
+pub type Splat = (usize, Vector3<Real>);
+
+/// Contributions of paths traced from the lights, which land on any pixel.
+struct Splats {
+    sum: Vec<Vector3<Real>>,
+    light_paths: u64,
+}
+
+/// Splats of a tile during the current pass, with the pixel offset.
+#[derive(Default)]
+struct TileSplats {
+    splats: Vec<Splat>,
+    light_paths: u64,
+}
+
+/// Accumulation buffer shared between the render workers and the viewer.
+/// Each tile has its own lock so workers never wait on each other.
+pub struct Film {
+    pub width: usize,
+    pub height: usize,
+    pub tiles: Vec<Tile>,
+    buffers: Vec<Mutex<Vec<FilmPixel>>>,
+    splats: Mutex<Splats>,
+    // Splats of every tile during the current pass, summed in tile order at its
+    // end so the image doesn't depend on which worker finished first
+    pass_splats: Vec<Mutex<TileSplats>>,
+    // Radiance estimated from photons, empty unless photon mapping is used
+    photon_radiance: Mutex<Vec<Vector3<Real>>>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize) -> Self {
+        let mut tiles = vec![];
+
+        // Tiles on the right and bottom edges are cropped to fit
+        for y in (0..height).step_by(TILE_SIZE) {
+            for x in (0..width).step_by(TILE_SIZE) {
+                tiles.push(Tile {
+                    x,
+                    y,
+                    width: TILE_SIZE.min(width - x),
+                    height: TILE_SIZE.min(height - y),
+                });
+            }
+        }
+
+        let buffers = tiles
+            .iter()
+            .map(|tile| Mutex::new(vec![FilmPixel::default(); tile.width * tile.height]))
+            .collect();
+        let pass_splats = tiles.iter().map(|_| Mutex::default()).collect();
+
+        Self {
+            width,
+            height,
+            tiles,
+            buffers,
+            splats: Mutex::new(Splats {
+                sum: vec![Vector3::zeros(); width * height],
+                light_paths: 0,
+            }),
+            pass_splats,
+            photon_radiance: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Add the pixels of a rendered tile, stored row by row, to the accumulation buffer.
+    pub fn add_tile(&self, tile_index: usize, pixels: &[FilmPixel]) {
+        let mut buffer = self.buffers[tile_index].lock().unwrap();
+
+        for (accumulated, pixel) in buffer.iter_mut().zip(pixels) {
+            accumulated.sum += pixel.sum;
+            accumulated.sum_squares += pixel.sum_squares;
+            accumulated.weight += pixel.weight;
+        }
+    }
+
+    /// Render every pixel of a tile with `render(x, y, accumulated)`, given what
+    /// the pixel accumulated in the previous passes, and add them to the tile.
+    /// `pixels` and `accumulated` are buffers reused from tile to tile. Returns the
+    /// number of samples taken.
+    pub fn render_tile<F>(
+        &self,
+        tile_index: usize,
+        pixels: &mut Vec<FilmPixel>,
+        accumulated: &mut Vec<FilmPixel>,
+        mut render: F,
+    ) -> usize
+    where
+        F: FnMut(usize, usize, &FilmPixel) -> FilmPixel,
+    {
+        let tile = self.tiles[tile_index];
+        self.read_tile(tile_index, accumulated);
+
+        pixels.clear();
+        for y in tile.y..tile.y + tile.height {
+            for x in tile.x..tile.x + tile.width {
+                let offset = (y - tile.y) * tile.width + x - tile.x;
+                pixels.push(render(x, y, &accumulated[offset]));
+            }
+        }
+
+        self.add_tile(tile_index, pixels);
+        pixels.iter().map(|pixel| pixel.weight as usize).sum()
+    }
+
+    /// Add contributions of light paths traced by tile `tile_index` to the pixels
+    /// at the given offsets, once the pass ends. `light_paths` is the number of
+    /// paths traced, splatting or not.
+    pub fn add_splats(&self, tile_index: usize, splats: &[Splat], light_paths: u64) {
+        let mut buffer = self.pass_splats[tile_index].lock().unwrap();
+
+        buffer.splats.extend_from_slice(splats);
+        buffer.light_paths += light_paths;
+    }
+
+    /// Sum the splats of the pass into the image, tile after tile.
+    pub fn end_pass(&self) {
+        let mut buffer = self.splats.lock().unwrap();
+
+        for tile_splats in &self.pass_splats {
+            let mut tile_splats = tile_splats.lock().unwrap();
+            for (offset, splat) in tile_splats.splats.drain(..) {
+                buffer.sum[offset] += splat;
+            }
+            buffer.light_paths += tile_splats.light_paths;
+            tile_splats.light_paths = 0;
+        }
+    }
+
+    /// Replace the radiance estimated from photons, one value per pixel stored row
+    /// by row. It is added to the average of the samples.
+    pub fn set_photon_radiance(&self, radiance: Vec<Vector3<Real>>) {
+        *self.photon_radiance.lock().unwrap() = radiance;
+    }
+
+    /// Copy the accumulated pixels of a tile, stored row by row.
+    pub fn read_tile(&self, tile_index: usize, pixels: &mut Vec<FilmPixel>) {
+        pixels.clear();
+        pixels.extend_from_slice(&self.buffers[tile_index].lock().unwrap());
+    }
+
+    /// RGB8 image of the number of samples taken by every pixel, from black
+    /// (fewest) through red to yellow (most).
+    pub fn sample_heatmap(&self) -> Vec<u8> {
+        let mut samples = vec![0.; self.width * self.height];
+
+        for (tile, buffer) in self.tiles.iter().zip(&self.buffers) {
+            let buffer = buffer.lock().unwrap();
+            for (i, pixel) in buffer.iter().enumerate() {
+                samples[(tile.y + i / tile.width) * self.width + tile.x + i % tile.width] =
+                    pixel.weight;
+            }
+        }
+
+        let min = samples.iter().cloned().fold(Real::INFINITY, Real::min);
+        let max = samples.iter().cloned().fold(0., Real::max);
+        let range = (max - min).max(1.);
+
+        samples
+            .iter()
+            .flat_map(|&count| {
+                let x = (count - min) / range;
+                [
+                    ((2. * x).min(1.) * 255.) as u8,
+                    ((2. * x - 1.).max(0.) * 255.) as u8,
+                    0,
+                ]
+            })
+            .collect()
+    }
+
+    /// Write the current estimate of a tile into `image`, a row by row canvas.
+    pub fn resolve_tile(&self, tile_index: usize, image: &mut [Vector3<Real>]) {
+        let tile = &self.tiles[tile_index];
+        let buffer = self.buffers[tile_index].lock().unwrap();
+        let splats = self.splats.lock().unwrap();
+        let photon_radiance = self.photon_radiance.lock().unwrap();
+
+        // Each light path is a sample of the whole image, so splats are averaged
+        // over the paths traced per pixel
+        let splat_scale = if splats.light_paths > 0 {
+            (self.width * self.height) as Real / splats.light_paths as Real
+        } else {
+            0.
+        };
+
+        for (i, pixel) in buffer.iter().enumerate() {
+            let offset = (tile.y + i / tile.width) * self.width + tile.x + i % tile.width;
+            image[offset] = pixel.value() + splats.sum[offset] * splat_scale;
+            if let Some(radiance) = photon_radiance.get(offset) {
+                image[offset] += radiance;
+            }
+        }
+    }
+
+    /// Bring `image` up to date with a render event. Splats and photons of a pass
+    /// are only merged once it ends, after its tiles were reported, so the whole
+    /// image is resolved again then.
+    pub fn update(&self, event: &RenderEvent, image: &mut [Vector3<Real>]) {
+        match *event {
+            RenderEvent::TileDone(tile_index) => self.resolve_tile(tile_index, image),
+            RenderEvent::PassDone(_) | RenderEvent::Converged(_) => {
+                for tile_index in 0..self.tiles.len() {
+                    self.resolve_tile(tile_index, image);
+                }
+            }
+            RenderEvent::SetupDone => {}
+        }
+    }
+}
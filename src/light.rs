@@ -1,9 +1,11 @@
 use nalgebra::Vector3;
 use serde::Deserialize;
 
+use crate::precision::Real;
+
 #[derive(Copy, Clone, Debug, Deserialize)]
 pub struct PointLight {
-    pub position: Vector3<f64>,
-    pub intensity: f64,
-    pub color: Vector3<f64>
+    pub position: Vector3<Real>,
+    pub intensity: Real,
+    pub color: Vector3<Real>
 }
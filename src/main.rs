@@ -1,19 +1,36 @@
-use clap::Parser;
-use engine::Engine;
+use clap::{CommandFactory, ErrorKind, Parser};
+use engine::{Engine, MisHeuristic, RenderEvent, RenderSettings};
+use film::Film;
+use sampler::SamplerKind;
+use nalgebra::Vector3;
 use serde_yaml;
 use show_image::event::VirtualKeyCode;
 use show_image::{create_window, event, ImageInfo, ImageView};
 use std::error::Error;
 use std::fs::File;
 
+mod bidirectional;
+mod bvh;
+mod cache;
 mod camera;
 mod engine;
+mod film;
 mod light;
+mod medium;
 mod mesh;
+mod metropolis;
 mod objects;
+mod photon_mapping;
+mod precision;
 mod ray;
+mod rng;
+mod sampler;
 mod scene;
+mod simd;
+mod spectrum;
+mod subsurface;
 mod texture_material;
+mod volume;
 
 use {crate::ray::*, crate::scene::*};
 
@@ -21,6 +38,9 @@ use {crate::ray::*, crate::scene::*};
 pub enum RenderMode {
     Raytracer,
     Pathtracer,
+    Bidirectional,
+    PhotonMapping,
+    Metropolis,
 }
 
 #[derive(Parser, Debug)]
@@ -33,12 +53,69 @@ struct Args {
     cpu: usize,
     #[clap(short, long, default_value_t = 4)]
     step: u32,
+    /// Stop sampling pixels once the relative error of their mean is below this value
+    #[clap(short, long)]
+    adaptive: Option<precision::Real>,
+    /// Save a heatmap of the samples taken per pixel next to the image
+    #[clap(long)]
+    heatmap: Option<String>,
+    /// Seed of the random numbers, the same seed always renders the same image
+    #[clap(long, default_value_t = 0)]
+    seed: u64,
+    /// Sample generator, overrides the one of the scene file (independent by default)
+    #[clap(arg_enum, long)]
+    sampler: Option<SamplerKind>,
+    /// Weighting of light and BSDF samples for direct lighting
+    #[clap(arg_enum, long, default_value_t = MisHeuristic::Power)]
+    mis: MisHeuristic,
+    /// Bounces before russian roulette may end a path, overrides the scene file
+    #[clap(long)]
+    min_depth: Option<u32>,
+    /// Maximum number of bounces of a path, overrides the scene file
+    #[clap(long)]
+    max_depth: Option<u32>,
+    /// Photons shot by each pass of the photon mapping (one per pixel by default)
+    #[clap(long)]
+    photons: Option<usize>,
+    /// Radius around the visible points of the photon mapping in which photons
+    /// are gathered at the first pass, it shrinks as photons are found
+    #[clap(long, default_value_t = photon_mapping::DEFAULT_PHOTON_RADIUS)]
+    photon_radius: precision::Real,
+    /// Independent paths estimating the brightness of the image before the
+    /// Metropolis chains start
+    #[clap(long, default_value_t = metropolis::DEFAULT_BOOTSTRAP_SAMPLES)]
+    bootstrap_samples: usize,
+    /// Trace wavelengths instead of RGB channels, dispersing light through
+    /// refractive surfaces (pathtracer and Metropolis)
+    #[clap(long)]
+    spectral: bool,
+    /// Print the progress of the render
+    #[clap(short, long)]
+    verbose: bool,
+    /// Stop rendering after this many passes
+    #[clap(long)]
+    passes: Option<u32>,
 }
 
 #[show_image::main]
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
 
+    // Other modes would silently trace RGB
+    if args.spectral
+        && !matches!(
+            args.render_mode,
+            RenderMode::Pathtracer | RenderMode::Metropolis
+        )
+    {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--spectral is only supported by the pathtracer and metropolis render modes",
+            )
+            .exit();
+    }
+
     let file = File::open(&args.path)?;
     let scene: Scene = serde_yaml::from_reader(file)?;
 
@@ -49,44 +126,110 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let (width, height) = (engine.canvas_width, engine.canvas_height);
 
-    let receiver = engine.stream_render(args.render_mode, args.cpu, args.step);
-    let mut merged_buffer = receiver.recv().unwrap();
-    let mut render_count = 1.;
+    let (film, receiver) = engine.stream_render(RenderSettings {
+        render_mode: args.render_mode,
+        cpu: args.cpu,
+        sample_per_iteration: args.step,
+        adaptive_threshold: args.adaptive,
+        seed: args.seed,
+        sampler: args
+            .sampler
+            .or(scene.sampler)
+            .unwrap_or(SamplerKind::Independent),
+        mis_heuristic: args.mis,
+        min_depth: args
+            .min_depth
+            .or(scene.min_depth)
+            .unwrap_or(engine::DEFAULT_MIN_DEPTH),
+        max_depth: args
+            .max_depth
+            .or(scene.max_depth)
+            .unwrap_or(engine::DEFAULT_MAX_DEPTH),
+        photons_per_pass: args.photons.unwrap_or(width * height),
+        photon_radius: args.photon_radius,
+        bootstrap_samples: args.bootstrap_samples,
+        spectral: args.spectral,
+        max_passes: args.passes,
+    });
+    let mut merged_buffer = vec![Vector3::zeros(); width * height];
+
+    let title = match args.render_mode {
+        RenderMode::Raytracer => "Raytracer",
+        RenderMode::Pathtracer => "Pathtracer",
+        RenderMode::Bidirectional => "Bidirectional",
+        RenderMode::PhotonMapping => "Photon mapping",
+        RenderMode::Metropolis => "Metropolis",
+    };
 
     // Create a window with default options and display the image.
-    let window = create_window(if args.render_mode == RenderMode::Raytracer { "Raytracer" } else { "Pathtracer" }, Default::default())?;
+    let window = create_window(title, Default::default())?;
     let event_channel = window.event_channel()?;
 
-    for single_buffer in receiver {
-        let image_buffer = Engine::buffer_float_to_u8(&merged_buffer, args.render_mode);
+    let mut image_buffer = vec![];
+
+    // Wait for progress, then handle every other notification already queued before redrawing
+    while let Ok(event) = receiver.recv() {
+        for event in std::iter::once(event).chain(receiver.try_iter()) {
+            film.update(&event, &mut merged_buffer);
+            match event {
+                RenderEvent::SetupDone if args.verbose => println!("Setup done"),
+                RenderEvent::PassDone(pass) if args.verbose => println!("Pass {} done", pass + 1),
+                RenderEvent::Converged(passes) if args.verbose => {
+                    println!("Converged after {} passes", passes)
+                }
+                _ => {}
+            }
+        }
+
+        image_buffer = Engine::buffer_float_to_u8(&merged_buffer, args.render_mode);
         window.set_image(
-            if args.render_mode == RenderMode::Raytracer { "Raytracer" } else { "Pathtracer" },
+            title,
             ImageView::new(ImageInfo::rgb8(width as u32, height as u32), &image_buffer),
         )?;
 
-        for i in 0..merged_buffer.len() {
-            // Interpolate previous buffer with new data to reduce noise
-            merged_buffer[i] = merged_buffer[i] * (render_count / (render_count + 1.))
-                + single_buffer[i] * (1. / (render_count + 1.));
-        }
-
-        // Save image on press S, exit program on Escape
         while let Ok(event) = event_channel.try_recv() {
-            if let event::WindowEvent::KeyboardInput(event) = event {
-                if event.input.state.is_pressed() {
-                    if event.input.key_code == Some(VirtualKeyCode::Escape) {
-                        println!("Exit program");
-                        return Ok(());
-                    } else if event.input.key_code == Some(VirtualKeyCode::S) {
-                        println!("Save into output.png");
-                        Engine::save("output.png", &image_buffer, width, height).unwrap();
-                    }
-                }
+            if !handle_window_event(event, &args, &film, &image_buffer) {
+                return Ok(());
             }
         }
+    }
 
-        render_count += 1.;
+    // Every pixel converged or the last pass is done, keep the window open until Escape
+    if let Some(path) = &args.heatmap {
+        println!("Save sample heatmap into {}", path);
+        Engine::save(path, &film.sample_heatmap(), width, height)?;
+    }
+    while let Ok(event) = event_channel.recv() {
+        if !handle_window_event(event, &args, &film, &image_buffer) {
+            return Ok(());
+        }
     }
 
     Ok(())
 }
+
+/// Save image on press S, exit program on Escape. Returns false to exit.
+fn handle_window_event(
+    event: event::WindowEvent,
+    args: &Args,
+    film: &Film,
+    image_buffer: &[u8],
+) -> bool {
+    if let event::WindowEvent::KeyboardInput(event) = event {
+        if event.input.state.is_pressed() {
+            if event.input.key_code == Some(VirtualKeyCode::Escape) {
+                println!("Exit program");
+                return false;
+            } else if event.input.key_code == Some(VirtualKeyCode::S) {
+                println!("Save into output.png");
+                Engine::save("output.png", image_buffer, film.width, film.height).unwrap();
+                if let Some(path) = &args.heatmap {
+                    println!("Save sample heatmap into {}", path);
+                    Engine::save(path, &film.sample_heatmap(), film.width, film.height).unwrap();
+                }
+            }
+        }
+    }
+
+    true
+}
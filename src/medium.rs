@@ -0,0 +1,475 @@
+//! Participating media, in which light is absorbed and scattered between
+//! surfaces. A homogeneous medium either fills the whole scene (fog) or the
+//! inside of a closed object, set on its surface. Heterogeneous volumes (see
+//! `volume`) are crossed by rays on top of it.
+
+use nalgebra::Vector3;
+use serde::Deserialize;
+
+use crate::engine::{
+    Engine, RenderSettings, BSDF_DIMENSION, LIGHT_DIMENSION, LIGHT_PICK_DIMENSION,
+};
+use crate::objects::{orthonormal_basis, HitRecord, ObjectsTrait};
+use crate::precision::{consts::PI, Real, RAY_EPSILON};
+use crate::rng::SampleRng;
+use crate::sampler::{bounce_dimension, Sampler};
+use crate::spectrum::Wavelengths;
+use crate::texture_material::TextureMaterial;
+use crate::Ray;
+
+// Below this asymmetry the phase function is sampled as isotropic
+const ISOTROPIC_THRESHOLD: Real = 1e-3;
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct Medium {
+    // Fraction of light absorbed and scattered per unit of distance, per channel
+    pub sigma_a: Vector3<Real>,
+    pub sigma_s: Vector3<Real>,
+    // Henyey-Greenstein asymmetry, from -1 (backward) to 1 (forward scattering)
+    #[serde(default)]
+    pub g: Real,
+}
+
+/// Outcome of a free flight through a medium.
+pub enum FreeFlight {
+    // Scattered at distance `t` by a medium of phase asymmetry `g`, the weight
+    // includes the scattering coefficient. `emission` is the light the medium
+    // emits there towards the origin of the ray, already weighted.
+    Scattered {
+        t: Real,
+        g: Real,
+        weight: Vector3<Real>,
+        emission: Vector3<Real>,
+    },
+    // Went through to the end of the segment
+    Passed {
+        weight: Vector3<Real>,
+    },
+}
+
+impl Medium {
+    pub fn sigma_t(&self) -> Vector3<Real> {
+        self.sigma_a + self.sigma_s
+    }
+
+    /// Fraction of light going through `distance` of the medium.
+    pub fn transmittance(&self, distance: Real) -> Vector3<Real> {
+        (-self.sigma_t() * distance).map(Real::exp)
+    }
+
+    /// Sample the distance light travels along a segment of length `max_t` before
+    /// interacting with the medium. The channel driving the distance is picked by
+    /// `u` and the densities of the three channels are averaged, so colored media
+    /// are sampled without bias.
+    pub fn sample_free_flight(&self, max_t: Real, u: Real) -> FreeFlight {
+        let sigma_t = self.sigma_t();
+        let channel = ((u * 3.) as usize).min(2);
+        let u = u * 3. - channel as Real;
+
+        let distance = -(1. - u).ln() / sigma_t[channel];
+        let scattered = distance < max_t;
+        let t = if scattered { distance } else { max_t };
+
+        let transmittance = self.transmittance(t);
+        let density = if scattered {
+            sigma_t.component_mul(&transmittance)
+        } else {
+            transmittance
+        };
+        let pdf = density.sum() / 3.;
+        if pdf <= 0. {
+            return FreeFlight::Passed {
+                weight: Vector3::zeros(),
+            };
+        }
+
+        if scattered {
+            FreeFlight::Scattered {
+                t,
+                g: self.g,
+                weight: transmittance.component_mul(&self.sigma_s) / pdf,
+                emission: Vector3::zeros(),
+            }
+        } else {
+            FreeFlight::Passed {
+                weight: transmittance / pdf,
+            }
+        }
+    }
+}
+
+/// Henyey-Greenstein phase function, density of light travelling along
+/// `direction` being scattered towards `wi`.
+pub fn phase(g: Real, direction: &Vector3<Real>, wi: &Vector3<Real>) -> Real {
+    let cos_theta = direction.dot(wi);
+    let denominator = 1. + g * g - 2. * g * cos_theta;
+    (1. - g * g) / (4. * PI * denominator * denominator.sqrt())
+}
+
+/// Pick the direction light travelling along `direction` is scattered to, in
+/// proportion to the phase function which is also its density.
+pub fn sample_phase(
+    g: Real,
+    direction: &Vector3<Real>,
+    (u1, u2): (Real, Real),
+) -> (Vector3<Real>, Real) {
+    let cos_theta = if g.abs() < ISOTROPIC_THRESHOLD {
+        1. - 2. * u1
+    } else {
+        let square = (1. - g * g) / (1. - g + 2. * g * u1);
+        ((1. + g * g - square * square) / (2. * g)).clamp(-1., 1.)
+    };
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    let phi = 2. * PI * u2;
+
+    let (tangent, bitangent) = orthonormal_basis(direction);
+    let wi = (tangent * phi.cos() + bitangent * phi.sin()) * sin_theta + direction * cos_theta;
+
+    (wi, phase(g, direction, &wi))
+}
+
+/// Media around a point of a path: the homogeneous medium it lies in, and the
+/// random numbers tracking rays through the volumes of the scene.
+pub struct Media {
+    pub medium: Option<Medium>,
+    pub rng: SampleRng,
+}
+
+impl Engine {
+    /// Medium a ray travels through up to `hit`, the one filling the object when
+    /// the ray leaves it and the scene medium otherwise. Objects nested in a
+    /// filled object are assumed to be in the scene medium.
+    pub fn segment_medium(
+        &self,
+        ray: &Ray,
+        hit: Option<(&HitRecord, &dyn ObjectsTrait)>,
+    ) -> Option<Medium> {
+        match hit {
+            Some((record, obj)) if record.normal.dot(&ray.direction) > 0. => {
+                obj.get_texture().surface.medium.or(self.medium)
+            }
+            _ => self.medium,
+        }
+    }
+
+    /// Sample where `ray` first interacts with the media before `max_t`: the
+    /// volumes are tracked with the numbers of `media`, the homogeneous medium
+    /// picks its distance with `u`.
+    pub fn sample_media(
+        &self,
+        ray: &Ray,
+        max_t: Real,
+        u: Real,
+        media: &mut Media,
+        wavelengths: &Wavelengths,
+    ) -> FreeFlight {
+        let collision = self
+            .volumes
+            .iter()
+            .filter_map(|volume| {
+                let t = volume.sample_collision(ray, max_t, &mut media.rng)?;
+                Some((t, volume))
+            })
+            .min_by(|(t0, _), (t1, _)| t0.total_cmp(t1));
+        let end = collision.map_or(max_t, |(t, _)| t);
+
+        // Scattering by the medium before the volume is reached
+        let transmission = match media.medium {
+            Some(medium) => match medium.sample_free_flight(end, u) {
+                FreeFlight::Passed { weight } => weight,
+                scattered => return scattered,
+            },
+            None => Vector3::repeat(1.),
+        };
+
+        match collision {
+            Some((t, volume)) => FreeFlight::Scattered {
+                t,
+                g: volume.g,
+                weight: transmission.component_mul(&wavelengths.of(&volume.albedo)),
+                emission: transmission.component_mul(&wavelengths.of(&volume.emission(&ray.at(t)))),
+            },
+            None => FreeFlight::Passed {
+                weight: transmission,
+            },
+        }
+    }
+
+    /// Fraction of light going through the media along the first `distance` of
+    /// `ray`.
+    pub fn media_transmittance(
+        &self,
+        media: &mut Media,
+        ray: &Ray,
+        distance: Real,
+    ) -> Vector3<Real> {
+        let transmittance = media
+            .medium
+            .map_or(Vector3::repeat(1.), |medium| medium.transmittance(distance));
+        self.volumes
+            .iter()
+            .fold(transmittance, |transmittance, volume| {
+                transmittance * volume.transmittance(ray, distance, &mut media.rng)
+            })
+    }
+
+    /// Light scattered at `point` towards the origin of `ray`, the `bounce`-th ray
+    /// of its path, by a medium of phase asymmetry `g`: direct lighting, then the
+    /// path continued in a direction picked by the phase function.
+    #[allow(clippy::too_many_arguments)]
+    pub fn trace_medium_scattering(
+        &self,
+        settings: &RenderSettings,
+        g: Real,
+        point: Vector3<Real>,
+        ray: &Ray,
+        bounce: u32,
+        throughput: Vector3<Real>,
+        sampler: &mut dyn Sampler,
+        media: &mut Media,
+        wavelengths: &Wavelengths,
+    ) -> Vector3<Real> {
+        let dimension = bounce_dimension(bounce);
+
+        // Like the emitters found by the bounce, which stops at the maximum depth
+        let direct_lightning = if bounce + 1 < settings.max_depth {
+            self.sample_medium_lightning(
+                settings,
+                g,
+                point,
+                ray,
+                sampler,
+                dimension,
+                media,
+                wavelengths,
+            ) + self.point_lights_medium_lightning(g, point, ray, media, wavelengths)
+        } else {
+            Vector3::zeros()
+        };
+
+        // The phase function is sampled exactly, the path keeps its weight
+        let (wi, pdf) = sample_phase(
+            g,
+            &ray.direction,
+            sampler.get_2d(dimension + BSDF_DIMENSION),
+        );
+        direct_lightning
+            + self.trace_path(
+                settings,
+                &Ray::new(point, wi),
+                bounce + 1,
+                throughput,
+                sampler,
+                Some(pdf),
+                wavelengths,
+            )
+    }
+
+    /// Light reaching `point` from one emitter picked at random and scattered
+    /// towards the origin of `ray`, weighted against the chance of the phase
+    /// function finding the same light.
+    #[allow(clippy::too_many_arguments)]
+    fn sample_medium_lightning(
+        &self,
+        settings: &RenderSettings,
+        g: Real,
+        point: Vector3<Real>,
+        ray: &Ray,
+        sampler: &mut dyn Sampler,
+        dimension: u32,
+        media: &mut Media,
+        wavelengths: &Wavelengths,
+    ) -> Vector3<Real> {
+        if self.emitters.is_empty() {
+            return Vector3::zeros();
+        }
+
+        let pick = sampler.get_1d(dimension + LIGHT_PICK_DIMENSION);
+        let emitter = &self.objects[self.emitters
+            [((pick * self.emitters.len() as Real) as usize).min(self.emitters.len() - 1)]];
+
+        let sample =
+            match emitter.sample_surface(&point, sampler.get_2d(dimension + LIGHT_DIMENSION)) {
+                Some(sample) if sample.pdf > 0. => sample,
+                _ => return Vector3::zeros(),
+            };
+
+        let to_light = sample.point - point;
+        let distance = to_light.norm();
+        let direction = to_light / distance;
+
+        let shadow_ray = Ray::new(point, direction);
+        if self.occluded(
+            &shadow_ray,
+            self.camera.near_clipping_range,
+            distance - RAY_EPSILON,
+        ) {
+            return Vector3::zeros();
+        }
+
+        let TextureMaterial { color, surface } = emitter.get_texture();
+        let emittance = wavelengths.of(&color) * surface.emittance.map(|e| e.ke).unwrap_or(0.);
+        // The emitter was picked with probability 1 / emitters
+        let pdf = sample.pdf / self.emitters.len() as Real;
+        let phase = phase(g, &ray.direction, &direction);
+        let weight = settings.mis_heuristic.weight(pdf, phase);
+
+        emittance.component_mul(&self.media_transmittance(media, &shadow_ray, distance))
+            * weight
+            * phase
+            / pdf
+    }
+
+    /// Light reaching `point` from every point light and scattered towards the
+    /// origin of `ray`, dimmed by the transparent surfaces on the way.
+    fn point_lights_medium_lightning(
+        &self,
+        g: Real,
+        point: Vector3<Real>,
+        ray: &Ray,
+        media: &mut Media,
+        wavelengths: &Wavelengths,
+    ) -> Vector3<Real> {
+        let mut lightning = Vector3::zeros();
+
+        for light in &self.lights {
+            let to_light = light.position - point;
+            let distance = to_light.norm();
+            let direction = to_light / distance;
+
+            let shadow_ray = Ray::new(point, direction);
+            let transmittance =
+                self.transmittance(&shadow_ray, self.camera.near_clipping_range, distance);
+            if transmittance <= 0. {
+                continue;
+            }
+
+            let phase = phase(g, &ray.direction, &direction);
+            lightning += (light.intensity * transmittance * wavelengths.of(&light.color))
+                .component_mul(&self.media_transmittance(media, &shadow_ray, distance))
+                * phase
+                / (distance * distance);
+        }
+
+        lightning
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::tests::spheres_engine;
+
+    // Stratified numbers in [0, 1)
+    fn stratified(count: usize) -> impl Iterator<Item = Real> {
+        (0..count).map(move |i| (i as Real + 0.5) / count as Real)
+    }
+
+    #[test]
+    fn free_flights_integrate_to_the_transmittance() {
+        // Colored medium, each channel with its own extinction
+        let medium = Medium {
+            sigma_a: Vector3::new(0.1, 0.5, 0.),
+            sigma_s: Vector3::new(0.4, 0.2, 1.5),
+            g: 0.,
+        };
+        let count = 100_000;
+
+        for distance in [0.3, 1., 4.] {
+            let (mut passed, mut scattered) = (Vector3::zeros(), Vector3::zeros());
+            for u in stratified(count) {
+                match medium.sample_free_flight(distance, u) {
+                    FreeFlight::Passed { weight } => passed += weight,
+                    FreeFlight::Scattered { weight, .. } => scattered += weight,
+                }
+            }
+            passed /= count as Real;
+            scattered /= count as Real;
+
+            // Light going through, and light scattered somewhere along the segment
+            let transmittance = medium.transmittance(distance);
+            let albedo = medium.sigma_s.component_div(&medium.sigma_t());
+            let expected_scattered = albedo.component_mul(&(Vector3::repeat(1.) - transmittance));
+            assert!(
+                (passed - transmittance).amax() < 1e-3,
+                "{} {}",
+                passed,
+                transmittance
+            );
+            assert!(
+                (scattered - expected_scattered).amax() < 1e-3,
+                "{} {}",
+                scattered,
+                expected_scattered
+            );
+        }
+    }
+
+    #[test]
+    fn media_transmittance_is_analytic() {
+        let engine = spheres_engine(4, 4, &[]);
+        let medium = Medium {
+            sigma_a: Vector3::new(0.1, 0.5, 0.),
+            sigma_s: Vector3::new(0.4, 0.2, 1.5),
+            g: 0.,
+        };
+        let mut media = Media {
+            medium: Some(medium),
+            rng: SampleRng::new(7, 0, 0, 0),
+        };
+        let ray = Ray::new(Vector3::zeros(), Vector3::x());
+
+        let transmittance = engine.media_transmittance(&mut media, &ray, 2.);
+        let expected = (-medium.sigma_t() * 2.).map(Real::exp);
+        assert!((transmittance - expected).amax() < 1e-6);
+    }
+
+    #[test]
+    fn phase_sampling_matches_phase() {
+        let direction = Vector3::new(1., 2., -0.5).normalize();
+        // cos(theta) only depends on u1
+        let (bins, count1, count2) = (20, 20_000, 5);
+
+        for g in [-0.7, 0., 0.3, 0.8] {
+            // Share of the samples in slices of cos(theta) around the direction
+            let mut histogram = vec![0.; bins];
+            let mut mean_cosine = 0.;
+            for u1 in stratified(count1) {
+                for u2 in stratified(count2) {
+                    let (wi, pdf) = sample_phase(g, &direction, (u1, u2));
+                    assert!((wi.norm() - 1.).abs() < 1e-4);
+                    assert!((pdf - phase(g, &direction, &wi)).abs() < 1e-4 * pdf);
+
+                    let cos_theta = direction.dot(&wi);
+                    let bin = (((cos_theta + 1.) / 2. * bins as Real) as usize).min(bins - 1);
+                    histogram[bin] += 1. / (count1 * count2) as Real;
+                    mean_cosine += cos_theta / (count1 * count2) as Real;
+                }
+            }
+
+            // Henyey-Greenstein has a mean cosine of g
+            assert!((mean_cosine - g).abs() < 1e-3, "{} {}", g, mean_cosine);
+
+            // Integral of the phase function over each slice
+            let steps = 100;
+            for (bin, share) in histogram.iter().enumerate() {
+                let expected: Real = stratified(steps)
+                    .map(|step| {
+                        let cos_theta = -1. + 2. * (bin as Real + step) / bins as Real;
+                        let sin_theta = (1. - cos_theta * cos_theta).sqrt();
+                        let (tangent, _) = orthonormal_basis(&direction);
+                        let wi = direction * cos_theta + tangent * sin_theta;
+                        2. * PI * phase(g, &direction, &wi) * 2. / (bins * steps) as Real
+                    })
+                    .sum();
+                assert!(
+                    (share - expected).abs() < 2e-3,
+                    "{} {} {}",
+                    g,
+                    share,
+                    expected
+                );
+            }
+        }
+    }
+}
@@ -1,60 +1,145 @@
-use nalgebra::{Rotation3, Vector3};
+use nalgebra::{Rotation3, Similarity3, Translation3, UnitQuaternion, Vector3};
 use serde::Deserialize;
 use std::{
-    f64::consts::PI,
     fs::File,
     io::{self, BufRead, BufReader},
+    sync::Arc,
 };
 
 use crate::{
+    bvh::{Aabb, Bvh},
+    cache::{self, MeshData},
     engine::Engine,
-    objects::{Mesh, Triangle},
+    objects::{Instance, Mesh},
+    precision::{consts::PI, Real},
+    simd::LANES,
     texture_material::TextureMaterial,
 };
 
+/// Transformed vertex buffer and the vertex indices of every triangle of an obj file.
+pub struct ObjData {
+    pub vertices: Vec<Vector3<Real>>,
+    pub indices: Vec<[u32; 3]>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct MeshConfig {
     pub path: String,
-    pub origin: Vector3<f64>,
-    pub scale: f64,
-    pub rotation: Vector3<f64>,
+    pub origin: Vector3<Real>,
+    pub scale: Real,
+    pub rotation: Vector3<Real>,
+    pub textmat: TextureMaterial,
+}
+
+/// Material of an obj model shared by all its instances, unless they set their own.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModelConfig {
+    pub path: String,
     pub textmat: TextureMaterial,
 }
 
+/// Copy of an obj model placed in the scene. Every instance of the same file
+/// shares a single mesh, loaded untransformed.
+#[derive(Clone, Debug, Deserialize)]
+pub struct InstanceConfig {
+    pub path: String,
+    pub origin: Vector3<Real>,
+    pub scale: Real,
+    pub rotation: Vector3<Real>,
+    // Overrides the material of the model
+    #[serde(default)]
+    pub textmat: Option<TextureMaterial>,
+}
+
+impl InstanceConfig {
+    /// Config loading the shared mesh in object space, with the material of the model.
+    pub fn mesh_config(&self, textmat: TextureMaterial) -> MeshConfig {
+        MeshConfig {
+            path: self.path.clone(),
+            origin: Vector3::zeros(),
+            scale: 1.,
+            rotation: Vector3::zeros(),
+            textmat,
+        }
+    }
+
+    pub fn to_instance(&self, mesh: Arc<Mesh>) -> Instance {
+        let euler_angle = self.rotation / 180. * PI;
+        let rotation =
+            UnitQuaternion::from_euler_angles(euler_angle.x, euler_angle.y, euler_angle.z);
+
+        Instance::new(
+            mesh,
+            Similarity3::from_parts(Translation3::from(self.origin), rotation, self.scale),
+            self.textmat,
+        )
+    }
+}
+
 impl MeshConfig {
     pub fn convert_to_triangles(&self, engine: &mut Engine) -> () {
-        let faces = match self.parse_obj_file() {
-            Ok(res) => res,
-            Err(error) => panic!("Problem in parsing obj file '{}': {:?}'", self.path, error),
+        engine.add_object(Box::new(self.load()));
+    }
+
+    pub fn load(&self) -> Mesh {
+        // Reuse the geometry and BVH of a previous run if the obj and transform didn't change
+        let data = match cache::load(self) {
+            Some(data) => data,
+            None => {
+                let obj = match self.parse_obj_file() {
+                    Ok(obj) => obj,
+                    Err(error) => {
+                        panic!("Problem in parsing obj file '{}': {:?}'", self.path, error)
+                    }
+                };
+
+                let data = self.triangularization(obj);
+                if let Err(error) = cache::save(self, &data) {
+                    eprintln!(
+                        "Could not write mesh cache for '{}': {:?}",
+                        self.path, error
+                    );
+                }
+                data
+            }
         };
 
-        self.triangularization(engine, &faces);
+        Mesh::new(data, self.textmat)
     }
 
-    pub fn parse_obj_file(&self) -> Result<Vec<[Vector3<f64>; 3]>, io::Error> {
+    /// Reads the vertices and faces of the obj file. Polygons are split into a
+    /// fan of triangles.
+    pub fn parse_obj_file(&self) -> Result<ObjData, io::Error> {
         let f = BufReader::new(File::open(&self.path)?);
 
-        let mut vertices: Vec<Vector3<f64>> = Vec::new();
+        let mut vertices: Vec<Vector3<Real>> = Vec::new();
         let mut faces = Vec::new();
 
         let euler_angle = self.rotation / 180. * PI;
         let rotation_matrix =
             Rotation3::from_euler_angles(euler_angle.x, euler_angle.y, euler_angle.z);
 
-        for line in f.lines() {
-            let line = line.unwrap();
-
-            if line.is_empty() {
-                continue;
-            }
+        for (number, line) in f.lines().enumerate() {
+            let line = line?;
+            let invalid = |message: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", number + 1, message),
+                )
+            };
 
             let mut tokens = line.split_whitespace();
 
-            match tokens.next().unwrap() {
-                "v" => {
+            match tokens.next() {
+                Some("v") => {
                     let xyz = tokens
-                        .map(|val| val.parse::<f64>().unwrap())
-                        .collect::<Vec<f64>>();
+                        .take(3)
+                        .map(|val| val.parse::<Real>())
+                        .collect::<Result<Vec<Real>, _>>()
+                        .map_err(|error| invalid(format!("invalid vertex: {}", error)))?;
+                    if xyz.len() < 3 {
+                        return Err(invalid("vertex with less than 3 coordinates".into()));
+                    }
 
                     // Apply transformation to all verticies
                     vertices.push(
@@ -62,56 +147,144 @@ impl MeshConfig {
                             + self.origin,
                     );
                 }
-                "f" => {
-                    let v0v1v2 = tokens
-                        .map(|val| val.parse::<usize>().unwrap())
-                        .collect::<Vec<usize>>();
-
-                    // Obj model starts index at 1 instead of 0.
-                    let face = [
-                        vertices[v0v1v2[0] - 1],
-                        vertices[v0v1v2[1] - 1],
-                        vertices[v0v1v2[2] - 1],
-                    ];
-                    faces.push(face);
+                Some("f") => {
+                    let face = tokens
+                        .map(|val| parse_face_index(val, vertices.len()))
+                        .collect::<Option<Vec<u32>>>()
+                        .ok_or_else(|| invalid(format!("invalid face '{}'", line)))?;
+                    if face.len() < 3 {
+                        return Err(invalid("face with less than 3 vertices".into()));
+                    }
+
+                    for i in 1..face.len() - 1 {
+                        faces.push([face[0], face[i], face[i + 1]]);
+                    }
                 }
                 _ => {} // TODO: parse vt and vn
             }
         }
 
-        return Ok(faces);
+        if faces
+            .iter()
+            .flatten()
+            .any(|&v| v as usize >= vertices.len())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "face refers to a missing vertex",
+            ));
+        }
+
+        Ok(ObjData {
+            vertices,
+            indices: faces,
+        })
     }
 
-    pub fn triangularization(&self, engine: &mut Engine, faces: &Vec<[Vector3<f64>; 3]>) -> () {
-        let mut triangles = vec![];
-
-        for [v0, v1, v2] in faces {
-            
-            triangles.push(Triangle {
-                v0: *v0,
-                v1: *v1,
-                v2: *v2,
-                textmat: self.textmat,
-            });
-        }
+    pub fn triangularization(&self, obj: ObjData) -> MeshData {
+        let ObjData { vertices, indices } = obj;
 
         // Compute AABB
-        let mut bounds = [Vector3::zeros(); 2];
-        for triangle in faces {
-            for vertex in triangle {
-                bounds[0].x = vertex.x.min(bounds[0].x);
-                bounds[0].y = vertex.y.min(bounds[0].y);
-                bounds[0].z = vertex.z.min(bounds[0].z);
-                bounds[1].x = vertex.x.max(bounds[1].x);
-                bounds[1].y = vertex.y.max(bounds[1].y);
-                bounds[1].z = vertex.z.max(bounds[1].z);
-            }
+        let mut bounds = [
+            Vector3::repeat(Real::INFINITY),
+            Vector3::repeat(Real::NEG_INFINITY),
+        ];
+        for vertex in &vertices {
+            bounds[0] = bounds[0].inf(vertex);
+            bounds[1] = bounds[1].sup(vertex);
         }
 
-        engine.add_object(Box::new(Mesh {
-            triangles,
+        // Leaves hold up to one SIMD packet worth of triangles
+        let bvh = Bvh::new(
+            indices
+                .iter()
+                .enumerate()
+                .map(|(i, face)| {
+                    let face = face.map(|v| vertices[v as usize]);
+                    (i, Aabb::from_points(&face))
+                })
+                .collect(),
+            LANES,
+        );
+
+        MeshData {
+            vertices,
+            indices,
             bounds,
-            textmat: self.textmat,
-        }));
+            bvh,
+        }
+    }
+}
+
+/// Vertex index of a face token such as `7`, `7/2` or `7/2/5`, from 0. Texture
+/// and normal indices are skipped.
+fn parse_face_index(token: &str, vertex_count: usize) -> Option<u32> {
+    let vertex = token.split_once('/').map_or(token, |(vertex, _)| vertex);
+    let index = vertex.parse::<i64>().ok()?;
+
+    // Obj indices start at 1, negative ones count back from the last vertex
+    let index = match index {
+        0 => return None,
+        i if i > 0 => i - 1,
+        i => vertex_count as i64 + i,
+    };
+    u32::try_from(index).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    fn parse(name: &str, contents: &str) -> Result<ObjData, io::Error> {
+        let path = env::temp_dir().join(format!("raytracer-test-{}.obj", name));
+        fs::write(&path, contents).unwrap();
+
+        let config = MeshConfig {
+            path: path.to_string_lossy().into_owned(),
+            origin: Vector3::zeros(),
+            scale: 1.,
+            rotation: Vector3::zeros(),
+            textmat: Default::default(),
+        };
+        let result = config.parse_obj_file();
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn parse_obj_faces() {
+        let obj = parse(
+            "faces",
+            "# quad\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvt 0 0\nvn 0 0 1\n\n\
+             f 1/1/1 2/1/1 3//1\nf -4 -2 4/1\nf 1 2 3 4\n",
+        )
+        .unwrap();
+
+        assert_eq!(obj.vertices.len(), 4);
+        assert_eq!(
+            obj.indices,
+            vec![[0, 1, 2], [0, 2, 3], [0, 1, 2], [0, 2, 3]]
+        );
+    }
+
+    #[test]
+    fn parse_obj_rejects_invalid_faces() {
+        let vertices = "v 0 0 0\nv 1 0 0\nv 1 1 0\n";
+        for (name, face) in [
+            ("token", "f 1 2 x"),
+            ("zero", "f 0 1 2"),
+            ("missing", "f 1 2 4"),
+            ("before", "f -4 1 2"),
+            ("short", "f 1 2"),
+        ] {
+            let error = parse(name, &format!("{}{}\n", vertices, face)).err();
+            assert_eq!(
+                error.map(|error| error.kind()),
+                Some(io::ErrorKind::InvalidData),
+                "{}",
+                face
+            );
+        }
     }
 }
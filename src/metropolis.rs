@@ -0,0 +1,415 @@
+//! Primary sample space Metropolis light transport (Kelemen et al., "A Simple and
+//! Robust Mutation Strategy for the Metropolis Light Transport Algorithm", 2002).
+//! The random numbers read by `trace_path` are mutated along Markov chains which
+//! visit paths in proportion to their luminance, so the few paths carrying most
+//! of the light get most of the samples.
+
+use std::sync::{Mutex, OnceLock};
+
+use nalgebra::Vector3;
+use rand::Rng;
+
+use crate::engine::{Engine, Integrator, RenderSettings, WorkerState};
+use crate::film::{luminance, Film};
+use crate::precision::{consts::PI, Real};
+use crate::rng::SampleRng;
+use crate::sampler::Sampler;
+use crate::spectrum::Wavelengths;
+
+/// Independent samples estimating the brightness of the image before the chains start.
+pub const DEFAULT_BOOTSTRAP_SAMPLES: usize = 100_000;
+// Bootstrap samples taken by a worker at once
+const BOOTSTRAP_BATCH: usize = 4096;
+// Chance of a mutation drawing a whole new sample rather than moving the current one
+const LARGE_STEP_PROBABILITY: Real = 0.3;
+// Standard deviation of the moves of the small steps
+const MUTATION_SIGMA: Real = 0.01;
+// Dimensions picking the position on the image, before the ones of `trace_path`
+const FILM_DIMENSIONS: usize = 2;
+
+#[derive(Copy, Clone, Debug, Default)]
+struct PrimarySample {
+    value: Real,
+    // Iteration which last changed the value
+    last_modification: u64,
+    // State before the current iteration, restored when its sample is rejected
+    backup: Real,
+    backup_modification: u64,
+}
+
+/// Sample whose dimensions are mutated rather than drawn anew at each iteration.
+/// Dimensions are only updated when read, catching up with the steps they missed.
+pub struct MltSampler {
+    seed: u64,
+    rng: SampleRng,
+    samples: Vec<PrimarySample>,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+}
+
+impl MltSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: SampleRng::new(seed, 0, 0, 0),
+            samples: Vec::new(),
+            iteration: 1,
+            large_step: true,
+            last_large_step: 1,
+        }
+    }
+
+    /// Move to the next sample, a small or a large step away from the current one.
+    pub fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<Real>() < LARGE_STEP_PROBABILITY;
+    }
+
+    /// Keep the sample of the current iteration.
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    /// Go back to the sample before the current iteration.
+    pub fn reject(&mut self) {
+        for sample in &mut self.samples {
+            if sample.last_modification == self.iteration {
+                sample.value = sample.backup;
+                sample.last_modification = sample.backup_modification;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    /// Position on the whole image, in [0, 1)^2.
+    pub fn film_position(&mut self) -> (Real, Real) {
+        (self.get(0), self.get(1))
+    }
+
+    fn get(&mut self, dimension: usize) -> Real {
+        if dimension >= self.samples.len() {
+            self.samples.resize(dimension + 1, PrimarySample::default());
+        }
+        let sample = &mut self.samples[dimension];
+
+        // Decisions reading a dimension again see the value they already got
+        if sample.last_modification == self.iteration {
+            return sample.value;
+        }
+
+        // Untouched since the last large step, which would have drawn it anew
+        if sample.last_modification < self.last_large_step {
+            sample.value = self.rng.gen();
+            sample.last_modification = self.last_large_step;
+        }
+
+        sample.backup = sample.value;
+        sample.backup_modification = sample.last_modification;
+        if self.large_step {
+            sample.value = self.rng.gen();
+        } else {
+            // Gaussian move (Box-Muller), as large as the small steps missed
+            let (u1, u2): (Real, Real) = (self.rng.gen(), self.rng.gen());
+            let normal = (-2. * (1. - u1).ln()).sqrt() * (2. * PI * u2).cos();
+            let steps = (self.iteration - sample.last_modification) as Real;
+
+            sample.value += normal * MUTATION_SIGMA * steps.sqrt();
+            sample.value = (sample.value - sample.value.floor()).min(1. - Real::EPSILON);
+        }
+        sample.last_modification = self.iteration;
+
+        sample.value
+    }
+}
+
+impl Sampler for MltSampler {
+    /// Forget the chain and start over from an independent sample, drawn from the
+    /// random stream of the given pixel sample.
+    fn start_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.rng = SampleRng::new(self.seed, x, y, index);
+        self.samples.clear();
+        self.iteration = 1;
+        self.large_step = true;
+        self.last_large_step = 1;
+    }
+
+    fn get_1d(&mut self, dimension: u32) -> Real {
+        self.get(dimension as usize + FILM_DIMENSIONS)
+    }
+
+    fn get_2d(&mut self, dimension: u32) -> (Real, Real) {
+        (self.get_1d(dimension), self.get_1d(dimension + 1))
+    }
+}
+
+struct Chain {
+    sampler: MltSampler,
+    // Random numbers accepting or rejecting the mutations
+    rng: SampleRng,
+    // Current path
+    pixel: usize,
+    radiance: Vector3<Real>,
+    importance: Real,
+}
+
+/// Markov chains carried from pass to pass, one per tile, each one advanced by a
+/// single worker at a time. They start from the paths of a bootstrap done by the
+/// workers before the first pass.
+pub struct Metropolis {
+    chain_count: usize,
+    // Luminance of the bootstrap paths, one vector per batch
+    bootstrap: Vec<Mutex<Vec<Real>>>,
+    // Average luminance of the paths over the whole primary sample space
+    normalization: OnceLock<Real>,
+    chains: OnceLock<Vec<Mutex<Chain>>>,
+}
+
+impl Metropolis {
+    /// Metropolis with `chain_count` chains, started once the bootstrap is done.
+    pub fn new(settings: &RenderSettings, chain_count: usize) -> Self {
+        let batches = settings.bootstrap_samples.div_ceil(BOOTSTRAP_BATCH);
+        Self {
+            chain_count,
+            bootstrap: (0..batches).map(|_| Mutex::new(Vec::new())).collect(),
+            normalization: OnceLock::new(),
+            chains: OnceLock::new(),
+        }
+    }
+
+    /// Estimate the brightness of the image from the bootstrap paths, then start
+    /// the chains on paths picked among them in proportion to their luminance.
+    fn start_chains(&self, engine: &Engine, settings: &RenderSettings) {
+        let importances: Vec<Real> = self
+            .bootstrap
+            .iter()
+            .flat_map(|batch| std::mem::take(&mut *batch.lock().unwrap()))
+            .collect();
+
+        let total: Real = importances.iter().sum();
+        let normalization = total / importances.len().max(1) as Real;
+        self.normalization.get_or_init(|| normalization);
+        if normalization <= 0. {
+            // Nothing is lit, there is no path to start from
+            self.chains.get_or_init(Vec::new);
+            return;
+        }
+
+        let mut cumulated = 0.;
+        let distribution: Vec<Real> = importances
+            .iter()
+            .map(|importance| {
+                cumulated += importance;
+                cumulated
+            })
+            .collect();
+
+        let chains = (0..self.chain_count)
+            .map(|chain| {
+                let mut rng = SampleRng::new(settings.seed, chain, 0, 1);
+                let pick = rng.gen::<Real>() * total;
+                let bootstrap = distribution
+                    .partition_point(|&cumulated| cumulated <= pick)
+                    .min(importances.len() - 1);
+
+                // Replaying the random stream of the bootstrap sample gives its path back
+                let mut sampler = MltSampler::new(settings.seed);
+                sampler.start_sample(bootstrap, 0, 0);
+                let (pixel, radiance) = engine.metropolis_sample(settings, &mut sampler);
+
+                Mutex::new(Chain {
+                    sampler,
+                    rng,
+                    pixel,
+                    radiance,
+                    importance: luminance(&radiance),
+                })
+            })
+            .collect();
+        self.chains.get_or_init(|| chains);
+    }
+
+    /// Mutate the path of chain `chain` `mutations` times, pushing what each
+    /// mutation brings to the image to `splats` with the pixel offset. Both the
+    /// current and the proposed paths contribute, in proportion to their chance of
+    /// being the next one.
+    pub fn run_chain(
+        &self,
+        engine: &Engine,
+        settings: &RenderSettings,
+        chain: usize,
+        mutations: usize,
+        splats: &mut Vec<(usize, Vector3<Real>)>,
+    ) {
+        let mut chain = match self.chains.get().and_then(|chains| chains.get(chain)) {
+            Some(chain) => chain.lock().unwrap(),
+            None => return,
+        };
+        let normalization = self.normalization.get().copied().unwrap_or(0.);
+
+        for _ in 0..mutations {
+            chain.sampler.start_iteration();
+            let (pixel, radiance) = engine.metropolis_sample(settings, &mut chain.sampler);
+            let importance = luminance(&radiance);
+            let acceptance = (importance / chain.importance).min(1.);
+
+            if acceptance > 0. {
+                splats.push((pixel, radiance * normalization * acceptance / importance));
+            }
+            splats.push((
+                chain.pixel,
+                chain.radiance * normalization * (1. - acceptance) / chain.importance,
+            ));
+
+            if chain.rng.gen::<Real>() < acceptance {
+                chain.pixel = pixel;
+                chain.radiance = radiance;
+                chain.importance = importance;
+                chain.sampler.accept();
+            } else {
+                chain.sampler.reject();
+            }
+        }
+    }
+}
+
+impl Integrator for Metropolis {
+    fn setup_batch_count(&self, _settings: &RenderSettings) -> usize {
+        self.bootstrap.len()
+    }
+
+    // The luminance of the `i`-th bootstrap path is the one of the `i`-th stream
+    // of the sampler
+    fn render_setup_batch(
+        &self,
+        engine: &Engine,
+        settings: &RenderSettings,
+        batch: usize,
+        _worker: &mut WorkerState,
+    ) {
+        let mut importances = self.bootstrap[batch].lock().unwrap();
+        let mut sampler = MltSampler::new(settings.seed);
+
+        let first = batch * BOOTSTRAP_BATCH;
+        let last = (first + BOOTSTRAP_BATCH).min(settings.bootstrap_samples);
+        importances.clear();
+        for sample in first..last {
+            sampler.start_sample(sample, 0, 0);
+            importances.push(luminance(
+                &engine.metropolis_sample(settings, &mut sampler).1,
+            ));
+        }
+    }
+
+    fn end_setup(&self, engine: &Engine, settings: &RenderSettings) {
+        self.start_chains(engine, settings);
+    }
+
+    // Mutations move paths anywhere in the image, each tile only advances its
+    // chain by as many steps as it has pixel samples
+    fn render_tile(
+        &self,
+        engine: &Engine,
+        settings: &RenderSettings,
+        film: &Film,
+        _pass: u32,
+        tile_index: usize,
+        worker: &mut WorkerState,
+    ) -> usize {
+        let tile = film.tiles[tile_index];
+        let mutations = tile.width * tile.height * settings.sample_per_iteration as usize;
+
+        worker.splats.clear();
+        self.run_chain(engine, settings, tile_index, mutations, &mut worker.splats);
+        film.add_splats(tile_index, &worker.splats, mutations as u64);
+        mutations
+    }
+}
+
+impl Engine {
+    /// Pixel offset and radiance of the path driven by the current sample of
+    /// `sampler`, which also picks the pixel.
+    fn metropolis_sample(
+        &self,
+        settings: &RenderSettings,
+        sampler: &mut MltSampler,
+    ) -> (usize, Vector3<Real>) {
+        let (u, v) = sampler.film_position();
+        let x = ((u * self.canvas_width as Real) as usize).min(self.canvas_width - 1);
+        let y = ((v * self.canvas_height as Real) as usize).min(self.canvas_height - 1);
+
+        let ray = self.camera.create_ray(x, y, sampler);
+        let wavelengths = Wavelengths::for_path(settings, sampler);
+        let radiance = self.trace_path(
+            settings,
+            &ray,
+            0,
+            Vector3::repeat(1.),
+            sampler,
+            None,
+            &wavelengths,
+        );
+
+        (y * self.canvas_width + x, wavelengths.to_rgb(&radiance))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::tests::{render, test_settings};
+    use crate::RenderMode;
+
+    #[test]
+    fn normalization_matches_pathtraced_mean() {
+        // Grey sphere lit by the emissive sphere enclosing the scene
+        let yaml = "camera:
+  origin: [0, 0, -3]
+  forward: [0, 0, 1]
+  up: [0, 1, 0]
+  fov_x_deg: 60
+  near_clipping_range: 0.01
+  canvas_width: 16
+  canvas_height: 16
+lights: []
+spheres:
+  - { center: [0, 0, 0], radius: 1, textmat: { color: [0.5, 0.5, 0.5], surface: { diffuse: { kd: 1 }, specular: { ks: 0, ns: 1 }, reflection: { kr: 0 }, transmission: { kt: 0 } } } }
+  - { center: [0, 0, 0], radius: 10, textmat: { color: [1, 0.5, 0.2], surface: { emittance: { ke: 1 }, diffuse: { kd: 0 }, specular: { ks: 0, ns: 1 }, reflection: { kr: 0 }, transmission: { kt: 0 } } } }
+";
+        let engine = || Engine::from_scene(&serde_yaml::from_str(yaml).unwrap());
+
+        let settings = RenderSettings {
+            sample_per_iteration: 16,
+            bootstrap_samples: 20_000,
+            ..test_settings(RenderMode::Metropolis, 1)
+        };
+        let metropolis = Metropolis::new(&settings, 1);
+        let engine_mlt = engine();
+        let mut worker = WorkerState::new(&settings);
+        for batch in 0..metropolis.setup_batch_count(&settings) {
+            metropolis.render_setup_batch(&engine_mlt, &settings, batch, &mut worker);
+        }
+        metropolis.end_setup(&engine_mlt, &settings);
+        let normalization = metropolis.normalization.get().copied().unwrap();
+
+        let image = render(
+            engine(),
+            RenderSettings {
+                render_mode: RenderMode::Pathtracer,
+                ..settings
+            },
+            4,
+        );
+        let mean = image.iter().map(luminance).sum::<Real>() / image.len() as Real;
+
+        assert!(mean > 0.);
+        assert!(
+            (normalization - mean).abs() < 0.02 * mean,
+            "{} {}",
+            normalization,
+            mean
+        );
+    }
+}
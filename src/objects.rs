@@ -1,41 +1,114 @@
 extern crate nalgebra;
 
-use std::mem::swap;
+use std::sync::Arc;
 
-use nalgebra::Vector3;
+use nalgebra::{Point3, Similarity3, Vector3};
 use serde::Deserialize;
 
-use {crate::ray::Ray, crate::texture_material::TextureMaterial};
-
-const EPSILON: f64 = 1e-6;
+use {
+    crate::bvh::{Aabb, Bvh},
+    crate::cache::MeshData,
+    crate::precision::{consts::PI, Real, HIT_EPSILON},
+    crate::ray::Ray,
+    crate::simd::TrianglePacket,
+    crate::texture_material::TextureMaterial,
+};
 
 pub struct HitRecord {
-    pub t: f64,
-    pub point: Vector3<f64>,
-    pub normal: Vector3<f64>,
+    pub t: Real,
+    pub point: Vector3<Real>,
+    pub normal: Vector3<Real>,
 }
 
 impl HitRecord {
-    pub fn new(t: f64, point: Vector3<f64>, normal: Vector3<f64>) -> Self {
+    pub fn new(t: Real, point: Vector3<Real>, normal: Vector3<Real>) -> Self {
         Self { t, point, normal }
     }
 }
 
+/// Point picked on a surface by `ObjectsTrait::sample_surface`.
+pub struct SurfaceSample {
+    pub point: Vector3<Real>,
+    // Density of the direction towards `point`, with respect to solid angle
+    pub pdf: Real,
+}
+
 pub trait ObjectsTrait: Sync + Send {
     fn intersects(
         &self,
         ray: &Ray,
-        near_clipping_range: f64,
-        far_clipping_range: f64,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
     ) -> Option<HitRecord>;
 
+    /// Whether anything is hit between the clipping ranges. Unlike `intersects`
+    /// it may stop at the first hit found instead of searching the nearest one.
+    fn occluded(&self, ray: &Ray, near_clipping_range: Real, far_clipping_range: Real) -> bool {
+        self.intersects(ray, near_clipping_range, far_clipping_range)
+            .is_some()
+    }
+
     fn get_texture(&self) -> TextureMaterial;
+
+    /// World space bounds, None for unbounded objects which can't be put in a BVH.
+    fn bounding_box(&self) -> Option<Aabb>;
+
+    /// Whether `sample_surface` is implemented, so the object can be sampled as a light.
+    fn can_sample_surface(&self) -> bool {
+        false
+    }
+
+    /// Pick a point of the surface seen from `origin`, `sample` being uniform in [0, 1)^2.
+    fn sample_surface(
+        &self,
+        _origin: &Vector3<Real>,
+        _sample: (Real, Real),
+    ) -> Option<SurfaceSample> {
+        None
+    }
+
+    /// Density with respect to solid angle of `sample_surface` picking the point
+    /// of `record`, hit by a ray starting at `origin`.
+    fn surface_pdf(&self, _origin: &Vector3<Real>, _record: &HitRecord) -> Real {
+        0.
+    }
+
+    /// Pick a point uniformly over the whole surface, returned with its normal.
+    /// Implemented by the objects which implement `sample_surface`.
+    fn sample_area(&self, _sample: (Real, Real)) -> Option<(Vector3<Real>, Vector3<Real>)> {
+        None
+    }
+
+    /// Area of the surface, only needed by the objects implementing `sample_area`.
+    fn area(&self) -> Real {
+        0.
+    }
+}
+
+/// Map `(u1, u2)`, uniform in [0, 1)^2, to a direction uniform over the sphere.
+pub fn uniform_sphere((u1, u2): (Real, Real)) -> Vector3<Real> {
+    let z = 1. - 2. * u1;
+    let r = (1. - z * z).max(0.).sqrt();
+    let phi = 2. * PI * u2;
+
+    Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Two unit vectors completing `w` into an orthonormal basis.
+pub fn orthonormal_basis(w: &Vector3<Real>) -> (Vector3<Real>, Vector3<Real>) {
+    let u = if w.x.is_normal() {
+        Vector3::new(w.y, -w.x, 0.).normalize()
+    } else {
+        Vector3::new(0., -w.z, w.y).normalize()
+    };
+
+    (u, w.cross(&u))
 }
 
 #[derive(Copy, Clone, Debug, Deserialize)]
 pub struct Sphere {
-    pub center: Vector3<f64>,
-    pub radius: f64,
+    pub center: Vector3<Real>,
+    pub radius: Real,
     pub textmat: TextureMaterial,
 }
 
@@ -43,8 +116,8 @@ impl ObjectsTrait for Sphere {
     fn intersects(
         &self,
         ray: &Ray,
-        near_clipping_range: f64,
-        far_clipping_range: f64,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
     ) -> Option<HitRecord> {
         let oc = ray.origin - self.center;
 
@@ -74,12 +147,101 @@ impl ObjectsTrait for Sphere {
     fn get_texture(&self) -> TextureMaterial {
         return self.textmat;
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let radius = Vector3::repeat(self.radius.abs());
+        Some(Aabb::new(self.center - radius, self.center + radius))
+    }
+
+    fn can_sample_surface(&self) -> bool {
+        true
+    }
+
+    fn sample_surface(
+        &self,
+        origin: &Vector3<Real>,
+        (u1, u2): (Real, Real),
+    ) -> Option<SurfaceSample> {
+        let to_center = self.center - origin;
+        let distance_squared = to_center.norm_squared();
+        let radius_squared = self.radius * self.radius;
+
+        if distance_squared <= radius_squared {
+            // Inside the sphere every point is visible, sample the area uniformly
+            let normal = uniform_sphere((u1, u2));
+            let point = self.center + self.radius.abs() * normal;
+
+            let to_point = point - origin;
+            let cos_theta = normal.dot(&to_point.normalize()).abs();
+            if cos_theta < HIT_EPSILON {
+                return None;
+            }
+
+            return Some(SurfaceSample {
+                point,
+                pdf: to_point.norm_squared() / (4. * PI * radius_squared * cos_theta),
+            });
+        }
+
+        // Outside, sample the cone of directions subtended by the sphere
+        let sin_theta_max_squared = radius_squared / distance_squared;
+        let cos_theta_max = (1. - sin_theta_max_squared).max(0.).sqrt();
+        // Same as 1 - cos_theta_max, without the cancellation for small spheres
+        let cone_size = sin_theta_max_squared / (1. + cos_theta_max);
+
+        let cos_theta = 1. - u1 * cone_size;
+        let sin_theta_squared = (1. - cos_theta * cos_theta).max(0.);
+        let phi = 2. * PI * u2;
+
+        let distance = distance_squared.sqrt();
+        let w = to_center / distance;
+        let (u, v) = orthonormal_basis(&w);
+        let direction = w * cos_theta + (u * phi.cos() + v * phi.sin()) * sin_theta_squared.sqrt();
+
+        // Nearest intersection of the sampled direction with the sphere
+        let t = distance * cos_theta
+            - (radius_squared - distance_squared * sin_theta_squared)
+                .max(0.)
+                .sqrt();
+        let point = origin + direction * t;
+
+        Some(SurfaceSample {
+            point,
+            pdf: 1. / (2. * PI * cone_size),
+        })
+    }
+
+    fn surface_pdf(&self, origin: &Vector3<Real>, record: &HitRecord) -> Real {
+        let distance_squared = (self.center - origin).norm_squared();
+        let radius_squared = self.radius * self.radius;
+
+        if distance_squared <= radius_squared {
+            let to_point = record.point - origin;
+            let cos_theta = record.normal.dot(&to_point.normalize()).abs();
+            return to_point.norm_squared()
+                / (4. * PI * radius_squared * cos_theta.max(HIT_EPSILON));
+        }
+
+        let sin_theta_max_squared = radius_squared / distance_squared;
+        let cos_theta_max = (1. - sin_theta_max_squared).max(0.).sqrt();
+        1. / (2. * PI * sin_theta_max_squared / (1. + cos_theta_max))
+    }
+
+    fn sample_area(&self, sample: (Real, Real)) -> Option<(Vector3<Real>, Vector3<Real>)> {
+        let normal = uniform_sphere(sample);
+
+        Some((self.center + self.radius.abs() * normal, normal))
+    }
+
+    fn area(&self) -> Real {
+        4. * PI * self.radius * self.radius
+    }
 }
 
 #[derive(Copy, Clone, Debug, Deserialize)]
 pub struct Plane {
-    pub center: Vector3<f64>,
-    pub normal: Vector3<f64>,
+    pub center: Vector3<Real>,
+    pub normal: Vector3<Real>,
     pub textmat: TextureMaterial,
 }
 
@@ -87,12 +249,12 @@ impl ObjectsTrait for Plane {
     fn intersects(
         &self,
         ray: &Ray,
-        near_clipping_range: f64,
-        far_clipping_range: f64,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
     ) -> Option<HitRecord> {
         let denom = (-self.normal).dot(&ray.direction);
 
-        if denom <= EPSILON {
+        if denom <= HIT_EPSILON {
             return None;
         }
 
@@ -110,37 +272,48 @@ impl ObjectsTrait for Plane {
     fn get_texture(&self) -> TextureMaterial {
         return self.textmat;
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // Planes are infinite, they are tested separately from the BVH
+        None
+    }
 }
 
 #[derive(Copy, Clone, Debug, Deserialize)]
 pub struct Triangle {
-    pub v0: Vector3<f64>,
-    pub v1: Vector3<f64>,
-    pub v2: Vector3<f64>,
+    pub v0: Vector3<Real>,
+    pub v1: Vector3<Real>,
+    pub v2: Vector3<Real>,
     pub textmat: TextureMaterial,
 }
 
+impl Triangle {
+    pub fn normal(&self) -> Vector3<Real> {
+        -(self.v1 - self.v0).cross(&(self.v2 - self.v0)).normalize()
+    }
+}
+
 impl ObjectsTrait for Triangle {
     fn intersects(
         &self,
         ray: &Ray,
-        near_clipping_range: f64,
-        far_clipping_range: f64,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
     ) -> Option<HitRecord> {
         // Möller-Trumbore algorithm
 
         let v0v1 = self.v1 - self.v0;
         let v0v2 = self.v2 - self.v0;
         let p = (ray.direction).cross(&v0v2);
-        let det = v0v1.dot(&p) as f64;
+        let det = v0v1.dot(&p) as Real;
 
-        if det > -EPSILON && det < EPSILON {
+        if det > -HIT_EPSILON && det < HIT_EPSILON {
             return None; // Ray is parallel to triangle.
         }
 
         let inv_det = 1.0 / det;
         let s = ray.origin - self.v0;
-        let u = (s.dot(&p) * inv_det) as f64;
+        let u = (s.dot(&p) * inv_det) as Real;
 
         if u < 0.0 || u > 1.0 {
             return None;
@@ -153,7 +326,7 @@ impl ObjectsTrait for Triangle {
             return None;
         }
 
-        let t = v0v2.dot(&q) * inv_det as f64;
+        let t = v0v2.dot(&q) * inv_det as Real;
 
         if t < near_clipping_range || t > far_clipping_range {
             return None;
@@ -161,81 +334,386 @@ impl ObjectsTrait for Triangle {
 
         let intersection_point = ray.at(t);
 
-        let normal = -(self.v1 - self.v0).cross(&(self.v2 - self.v0)).normalize();
-
-        return Some(HitRecord::new(t, intersection_point, normal));
+        return Some(HitRecord::new(t, intersection_point, self.normal()));
     }
 
     fn get_texture(&self) -> TextureMaterial {
         return self.textmat;
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::from_points(&[self.v0, self.v1, self.v2]))
+    }
+
+    fn can_sample_surface(&self) -> bool {
+        true
+    }
+
+    fn sample_surface(
+        &self,
+        origin: &Vector3<Real>,
+        (u1, u2): (Real, Real),
+    ) -> Option<SurfaceSample> {
+        // Uniform barycentric coordinates
+        let su1 = u1.sqrt();
+        let (b0, b1) = (1. - su1, u2 * su1);
+        let point = self.v0 * b0 + self.v1 * b1 + self.v2 * (1. - b0 - b1);
+
+        let area = self.area();
+        let normal = self.normal();
+        let to_point = point - origin;
+        let cos_theta = normal.dot(&to_point.normalize()).abs();
+        if area <= 0. || cos_theta < HIT_EPSILON {
+            return None;
+        }
+
+        Some(SurfaceSample {
+            point,
+            pdf: to_point.norm_squared() / (area * cos_theta),
+        })
+    }
+
+    fn surface_pdf(&self, origin: &Vector3<Real>, record: &HitRecord) -> Real {
+        let area = self.area();
+        let to_point = record.point - origin;
+        let cos_theta = self.normal().dot(&to_point.normalize()).abs();
+        if area <= 0. || cos_theta < HIT_EPSILON {
+            return 0.;
+        }
+
+        to_point.norm_squared() / (area * cos_theta)
+    }
+
+    fn sample_area(&self, (u1, u2): (Real, Real)) -> Option<(Vector3<Real>, Vector3<Real>)> {
+        if self.area() <= 0. {
+            return None;
+        }
+
+        let su1 = u1.sqrt();
+        let (b0, b1) = (1. - su1, u2 * su1);
+        let point = self.v0 * b0 + self.v1 * b1 + self.v2 * (1. - b0 - b1);
+
+        Some((point, self.normal()))
+    }
+
+    fn area(&self) -> Real {
+        0.5 * (self.v1 - self.v0).cross(&(self.v2 - self.v0)).norm()
+    }
 }
 
+/// Indexed triangle mesh, faces share a single vertex buffer.
 pub struct Mesh {
-    pub triangles: Vec<Triangle>,
-    pub bounds: [Vector3<f64>; 2],
+    pub vertices: Vec<Vector3<Real>>,
+    pub indices: Vec<[u32; 3]>,
+    pub bounds: [Vector3<Real>; 2],
     pub textmat: TextureMaterial,
+    // Leaves hold up to one SIMD packet of triangles, referred to by position in
+    // `indices`. Packets are gathered when a leaf is visited, so triangles are
+    // never stored twice.
+    pub bvh: Bvh,
+}
+
+impl Mesh {
+    pub fn new(data: MeshData, textmat: TextureMaterial) -> Self {
+        Self {
+            vertices: data.vertices,
+            indices: data.indices,
+            bounds: data.bounds,
+            textmat,
+            bvh: data.bvh,
+        }
+    }
+
+    pub fn triangle(&self, index: usize) -> [Vector3<Real>; 3] {
+        let [i0, i1, i2] = self.indices[index];
+        [
+            self.vertices[i0 as usize],
+            self.vertices[i1 as usize],
+            self.vertices[i2 as usize],
+        ]
+    }
+
+    pub fn normal(&self, index: usize) -> Vector3<Real> {
+        let [v0, v1, v2] = self.triangle(index);
+        -(v1 - v0).cross(&(v2 - v0)).normalize()
+    }
 }
 
 impl ObjectsTrait for Mesh {
     fn intersects(
         &self,
         ray: &Ray,
-        near_clipping_range: f64,
-        far_clipping_range: f64,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
     ) -> Option<HitRecord> {
-        if !self.intersect_aabb(ray) {
-            return None;
-        }
-
-        let mut min_t = far_clipping_range;
         let mut min_obj = None;
 
-        for object in &self.triangles {
-            // Find the nearest root.
-            if let Some(record) = object.intersects(&ray, near_clipping_range, min_t) {
-                min_t = record.t;
-                min_obj = Some(record);
-            };
-        }
+        // Find the nearest root, the BVH is walked front to back and skips
+        // every node further than the closest triangle found so far.
+        self.bvh.traverse_leaves(
+            ray,
+            near_clipping_range,
+            far_clipping_range,
+            |leaf, min_t| {
+                let packet = TrianglePacket::gather(&self.vertices, &self.indices, leaf);
+                let (lane, t) = packet.intersects(ray, near_clipping_range, min_t)?;
+                min_obj = Some((packet.triangles[lane], t));
+                Some(t)
+            },
+        );
+
+        let (triangle, t) = min_obj?;
+        Some(HitRecord::new(t, ray.at(t), self.normal(triangle)))
+    }
 
-        return min_obj;
+    fn occluded(&self, ray: &Ray, near_clipping_range: Real, far_clipping_range: Real) -> bool {
+        self.bvh
+            .occluded_leaves(ray, near_clipping_range, far_clipping_range, |leaf| {
+                TrianglePacket::gather(&self.vertices, &self.indices, leaf)
+                    .intersects(ray, near_clipping_range, far_clipping_range)
+                    .is_some()
+            })
     }
 
     fn get_texture(&self) -> TextureMaterial {
         return self.textmat;
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::new(self.bounds[0], self.bounds[1]))
+    }
 }
 
-impl Mesh {
-    fn intersect_aabb(&self, ray: &Ray) -> bool {
-        let mut tmin = (self.bounds[0].x - ray.origin.x) / ray.direction.x;
-        let mut tmax = (self.bounds[1].x - ray.origin.x) / ray.direction.x;
-        if tmin > tmax {
-            swap(&mut tmin, &mut tmax);
-        }
-        let mut tymin = (self.bounds[0].y - ray.origin.y) / ray.direction.y;
-        let mut tymax = (self.bounds[1].y - ray.origin.y) / ray.direction.y;
-        if tymin > tymax {
-            swap(&mut tymin, &mut tymax);
-        }
-        if tmin > tymax || tymin > tmax {
-            return false;
-        }
-        if tymin > tmin {
-            tmin = tymin;
+/// Shared mesh placed with its own transform and material. Rays are moved to
+/// the object space of the mesh instead of storing transformed triangles.
+pub struct Instance {
+    pub mesh: Arc<Mesh>,
+    pub object_to_world: Similarity3<Real>,
+    pub world_to_object: Similarity3<Real>,
+    // Material of the mesh when None
+    pub textmat: Option<TextureMaterial>,
+    bounds: Aabb,
+}
+
+impl Instance {
+    pub fn new(
+        mesh: Arc<Mesh>,
+        object_to_world: Similarity3<Real>,
+        textmat: Option<TextureMaterial>,
+    ) -> Self {
+        // World bounds are the bounds of the transformed corners of the mesh bounds
+        let [min, max] = mesh.bounds;
+        let corners = (0..8)
+            .map(|i| {
+                let corner = Vector3::new(
+                    if i & 1 == 0 { min.x } else { max.x },
+                    if i & 2 == 0 { min.y } else { max.y },
+                    if i & 4 == 0 { min.z } else { max.z },
+                );
+                object_to_world
+                    .transform_point(&Point3::from(corner))
+                    .coords
+            })
+            .collect::<Vec<_>>();
+
+        Self {
+            mesh,
+            object_to_world,
+            world_to_object: object_to_world.inverse(),
+            textmat,
+            bounds: Aabb::from_points(&corners),
         }
-        if tymax < tmax {
-            tmax = tymax;
+    }
+
+    // The direction isn't normalized so distances along the ray are the
+    // same in both spaces
+    fn object_ray(&self, ray: &Ray) -> Ray {
+        Ray::new(
+            self.world_to_object
+                .transform_point(&Point3::from(ray.origin))
+                .coords,
+            self.world_to_object.transform_vector(&ray.direction),
+        )
+    }
+}
+
+impl ObjectsTrait for Instance {
+    fn intersects(
+        &self,
+        ray: &Ray,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
+    ) -> Option<HitRecord> {
+        let record = self.mesh.intersects(
+            &self.object_ray(ray),
+            near_clipping_range,
+            far_clipping_range,
+        )?;
+
+        // Scale is uniform, rotating the normal is enough
+        let normal = self.object_to_world.isometry.rotation * record.normal;
+
+        Some(HitRecord::new(record.t, ray.at(record.t), normal))
+    }
+
+    fn occluded(&self, ray: &Ray, near_clipping_range: Real, far_clipping_range: Real) -> bool {
+        self.mesh.occluded(
+            &self.object_ray(ray),
+            near_clipping_range,
+            far_clipping_range,
+        )
+    }
+
+    fn get_texture(&self) -> TextureMaterial {
+        self.textmat.unwrap_or(self.mesh.textmat)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use nalgebra::{Translation3, UnitQuaternion};
+
+    use super::*;
+    use crate::mesh::{MeshConfig, ObjData};
+    use crate::rng::SampleRng;
+
+    fn random_vector(rng: &mut SampleRng, scale: Real) -> Vector3<Real> {
+        Vector3::new(rng.gen(), rng.gen(), rng.gen()).map(|x: Real| (x - 0.5) * scale)
+    }
+
+    /// Small triangles scattered in a box, as a mesh and as separate triangles.
+    fn triangle_soup(rng: &mut SampleRng, count: usize) -> (Mesh, Vec<Triangle>) {
+        let triangles: Vec<Triangle> = (0..count)
+            .map(|_| {
+                let center = random_vector(rng, 10.);
+                Triangle {
+                    v0: center + random_vector(rng, 1.),
+                    v1: center + random_vector(rng, 1.),
+                    v2: center + random_vector(rng, 1.),
+                    textmat: Default::default(),
+                }
+            })
+            .collect();
+
+        let obj = ObjData {
+            vertices: triangles
+                .iter()
+                .flat_map(|triangle| [triangle.v0, triangle.v1, triangle.v2])
+                .collect(),
+            indices: (0..count as u32)
+                .map(|i| [3 * i, 3 * i + 1, 3 * i + 2])
+                .collect(),
+        };
+        let config = MeshConfig {
+            path: String::new(),
+            origin: Vector3::zeros(),
+            scale: 1.,
+            rotation: Vector3::zeros(),
+            textmat: Default::default(),
+        };
+        let mesh = Mesh::new(config.triangularization(obj), Default::default());
+        (mesh, triangles)
+    }
+
+    #[test]
+    fn mesh_bvh_matches_brute_force() {
+        let mut rng = SampleRng::new(2, 0, 0, 0);
+        let (mesh, triangles) = triangle_soup(&mut rng, 500);
+
+        let mut hits = 0;
+        for _ in 0..1000 {
+            // Aimed at the soup so that many rays hit
+            let origin = random_vector(&mut rng, 30.);
+            let target = random_vector(&mut rng, 8.);
+            let ray = Ray::new(origin, (target - origin).normalize());
+            let (near, far) = (1e-4, 1e3);
+
+            let brute_force = triangles
+                .iter()
+                .filter_map(|triangle| triangle.intersects(&ray, near, far).map(|hit| hit.t))
+                .fold(None, |min: Option<Real>, t| {
+                    Some(min.map_or(t, |min| min.min(t)))
+                });
+            let nearest = mesh.intersects(&ray, near, far).map(|hit| hit.t);
+
+            match (nearest, brute_force) {
+                (Some(t), Some(expected)) => assert!((t - expected).abs() < 1e-3 * expected),
+                (nearest, expected) => assert_eq!(nearest, expected),
+            }
+            assert_eq!(mesh.occluded(&ray, near, far), brute_force.is_some());
+            hits += brute_force.is_some() as usize;
         }
-        let mut tzmin = (self.bounds[0].z - ray.origin.z) / ray.direction.z;
-        let mut tzmax = (self.bounds[1].z - ray.origin.z) / ray.direction.z;
-        if tzmin > tzmax {
-            swap(&mut tzmin, &mut tzmax);
+        assert!(hits > 300);
+    }
+
+    #[test]
+    fn instance_matches_transformed_triangles() {
+        let mut rng = SampleRng::new(6, 0, 0, 0);
+        let (mesh, triangles) = triangle_soup(&mut rng, 500);
+
+        let rotation = UnitQuaternion::from_euler_angles(0.3, -1.2, 2.);
+        let object_to_world =
+            Similarity3::from_parts(Translation3::new(4., -2., 7.), rotation, 2.5);
+        let textmat = TextureMaterial {
+            color: Vector3::new(0.1, 0.2, 0.3),
+            ..Default::default()
+        };
+        let instance = Instance::new(Arc::new(mesh), object_to_world, None);
+        let overridden = Instance::new(instance.mesh.clone(), object_to_world, Some(textmat));
+        assert_eq!(instance.get_texture().color, instance.mesh.textmat.color);
+        assert_eq!(overridden.get_texture().color, textmat.color);
+
+        let to_world = |v: Vector3<Real>| object_to_world.transform_point(&Point3::from(v)).coords;
+        let world_triangles: Vec<Triangle> = triangles
+            .iter()
+            .map(|triangle| Triangle {
+                v0: to_world(triangle.v0),
+                v1: to_world(triangle.v1),
+                v2: to_world(triangle.v2),
+                textmat: Default::default(),
+            })
+            .collect();
+
+        let mut hits = 0;
+        for _ in 0..1000 {
+            let origin = to_world(random_vector(&mut rng, 30.));
+            let target = to_world(random_vector(&mut rng, 8.));
+            let ray = Ray::new(origin, (target - origin).normalize());
+            let (near, far) = (1e-4, 1e3);
+
+            let expected = world_triangles
+                .iter()
+                .filter_map(|triangle| {
+                    let hit = triangle.intersects(&ray, near, far)?;
+                    Some((hit.t, triangle.normal()))
+                })
+                .min_by(|(t0, _), (t1, _)| t0.total_cmp(t1));
+
+            match (instance.intersects(&ray, near, far), expected) {
+                (Some(hit), Some((t, normal))) => {
+                    assert!((hit.t - t).abs() < 1e-3 * t);
+                    assert!((hit.point - ray.at(t)).norm() < 1e-3 * t);
+                    assert!((hit.normal - normal).norm() < 1e-3);
+                    hits += 1;
+                }
+                (hit, expected) => assert_eq!(hit.is_some(), expected.is_some()),
+            }
+            assert_eq!(instance.occluded(&ray, near, far), expected.is_some());
         }
-        if tmin > tzmax || tzmin > tmax {
-            return false;
+        assert!(hits > 300);
+
+        let bounds = instance.bounding_box().unwrap();
+        for triangle in &world_triangles {
+            for vertex in [triangle.v0, triangle.v1, triangle.v2] {
+                assert!((vertex - bounds.min).min() > -1e-3);
+                assert!((bounds.max - vertex).min() > -1e-3);
+            }
         }
-        true
     }
 }
@@ -0,0 +1,636 @@
+//! Stochastic progressive photon mapping (Hachisuka and Jensen, "Stochastic
+//! Progressive Photon Mapping", 2009). Each pass finds the first diffuse surface
+//! seen through every pixel, then shoots photons from the lights and gathers the
+//! ones landing around those visible points. The gather radius of a pixel shrinks
+//! as it collects photons, so the image converges over the passes.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+
+use nalgebra::Vector3;
+
+use crate::engine::{
+    pick_lobe, Engine, Integrator, RenderSettings, WorkerState, BSDF_DIMENSION, LIGHT_DIMENSION,
+    LIGHT_PICK_DIMENSION, LOBE_DIMENSION, RUSSIAN_ROULETTE_DIMENSION,
+};
+use crate::film::{Film, FilmPixel, Splat};
+use crate::objects::uniform_sphere;
+use crate::precision::{consts::PI, Real, RAY_EPSILON};
+use crate::ray::Ray;
+use crate::sampler::{bounce_dimension, Sampler};
+use crate::spectrum::{Wavelengths, REFERENCE_WAVELENGTH};
+use crate::texture_material::TextureMaterial;
+
+// Gather radius of every pixel at the first pass when the command line doesn't set it
+pub const DEFAULT_PHOTON_RADIUS: Real = 0.1;
+// Share of the photons found in a pass kept by the pixel statistics, the lower
+// the faster the radius shrinks
+const ALPHA: Real = 2. / 3.;
+// Photons a worker shoots before taking the next ones of the pass
+const PHOTON_BATCH: usize = 1024;
+
+/// First diffuse surface seen through a pixel during a pass.
+#[derive(Copy, Clone, Debug)]
+pub struct VisiblePoint {
+    // Offset of the pixel in the image
+    pub pixel: usize,
+    pub point: Vector3<Real>,
+    pub normal: Vector3<Real>,
+    // Direction back to the camera
+    pub wo: Vector3<Real>,
+    // Throughput of the camera path times the diffuse BSDF of the surface
+    pub weight: Vector3<Real>,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct PhotonPixel {
+    radius: Real,
+    // Photons kept by the statistics so far
+    photons: Real,
+    // Flux gathered over the passes, scaled down with the area of the radius
+    flux: Vector3<Real>,
+    visible_point: Option<VisiblePoint>,
+    // Weighted flux and number of the photons found during the current pass
+    pass_flux: Vector3<Real>,
+    pass_photons: u32,
+}
+
+/// Visible points of a pass bucketed in a uniform grid, with cells as large as
+/// the largest gather radius so a point overlaps at most 8 of them.
+pub struct PhotonGrid {
+    cell_size: Real,
+    points: Vec<(VisiblePoint, Real)>,
+    cells: HashMap<[i64; 3], Vec<usize>>,
+}
+
+impl PhotonGrid {
+    fn cell(&self, point: &Vector3<Real>) -> [i64; 3] {
+        let cell = point.map(|coordinate| (coordinate / self.cell_size).floor() as i64);
+        [cell.x, cell.y, cell.z]
+    }
+
+    /// Visible points whose gather radius contains `point`.
+    pub fn gather<'a>(
+        &'a self,
+        point: &'a Vector3<Real>,
+    ) -> impl Iterator<Item = &'a VisiblePoint> {
+        self.cells
+            .get(&self.cell(point))
+            .into_iter()
+            .flatten()
+            .map(|index| &self.points[*index])
+            .filter(move |(visible_point, radius)| {
+                (visible_point.point - point).norm_squared() < radius * radius
+            })
+            .map(|(visible_point, _)| visible_point)
+    }
+}
+
+/// Statistics of every pixel carried from pass to pass.
+pub struct PhotonMap {
+    pixels: Mutex<Vec<PhotonPixel>>,
+    grid: RwLock<PhotonGrid>,
+    // Photons found by every batch of the current pass, with the pixel offset.
+    // They are summed in batch order at the end of the pass so the flux doesn't
+    // depend on which worker finished first.
+    batches: Vec<Mutex<Vec<Splat>>>,
+}
+
+impl PhotonMap {
+    pub fn new(pixel_count: usize, radius: Real, photons_per_pass: usize) -> Self {
+        let pixel = PhotonPixel {
+            radius,
+            photons: 0.,
+            flux: Vector3::zeros(),
+            visible_point: None,
+            pass_flux: Vector3::zeros(),
+            pass_photons: 0,
+        };
+
+        Self {
+            pixels: Mutex::new(vec![pixel; pixel_count]),
+            grid: RwLock::new(PhotonGrid {
+                cell_size: radius,
+                points: Vec::new(),
+                cells: HashMap::new(),
+            }),
+            batches: (0..photons_per_pass.div_ceil(PHOTON_BATCH))
+                .map(|_| Mutex::default())
+                .collect(),
+        }
+    }
+
+    fn add_visible_points(&self, visible_points: &[VisiblePoint]) {
+        let mut pixels = self.pixels.lock().unwrap();
+
+        for visible_point in visible_points {
+            pixels[visible_point.pixel].visible_point = Some(*visible_point);
+        }
+    }
+
+    /// Bucket the visible points of the pass, once they are all added.
+    fn build_grid(&self) {
+        let pixels = self.pixels.lock().unwrap();
+        let mut grid = self.grid.write().unwrap();
+
+        grid.points.clear();
+        grid.cells.clear();
+        for pixel in pixels.iter() {
+            if let Some(visible_point) = pixel.visible_point {
+                grid.points.push((visible_point, pixel.radius));
+            }
+        }
+        grid.cell_size = grid
+            .points
+            .iter()
+            .map(|(_, radius)| *radius)
+            .fold(0., Real::max);
+        if grid.cell_size <= 0. {
+            return;
+        }
+
+        for index in 0..grid.points.len() {
+            let (visible_point, radius) = grid.points[index];
+            let min = grid.cell(&visible_point.point.add_scalar(-radius));
+            let max = grid.cell(&visible_point.point.add_scalar(radius));
+            for x in min[0]..=max[0] {
+                for y in min[1]..=max[1] {
+                    for z in min[2]..=max[2] {
+                        grid.cells.entry([x, y, z]).or_default().push(index);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Add the photons found by the batches of the pass `pass`, then shrink
+    /// the radius of the pixels which found some and give the film the radiance
+    /// estimated from every photon shot so far.
+    fn update_pixels(&self, film: &Film, pass: u32, photons_per_pass: usize) {
+        let mut pixels = self.pixels.lock().unwrap();
+        let shot_photons = (pass as Real + 1.) * photons_per_pass as Real;
+
+        for batch in &self.batches {
+            for (offset, flux) in batch.lock().unwrap().drain(..) {
+                pixels[offset].pass_flux += flux;
+                pixels[offset].pass_photons += 1;
+            }
+        }
+
+        let radiance = pixels
+            .iter_mut()
+            .map(|pixel| {
+                if pixel.pass_photons > 0 {
+                    let found = pixel.pass_photons as Real;
+                    let photons = pixel.photons + ALPHA * found;
+                    let radius = pixel.radius * (photons / (pixel.photons + found)).sqrt();
+
+                    pixel.flux = (pixel.flux + pixel.pass_flux) * (radius / pixel.radius).powi(2);
+                    pixel.photons = photons;
+                    pixel.radius = radius;
+                }
+                pixel.visible_point = None;
+                pixel.pass_flux = Vector3::zeros();
+                pixel.pass_photons = 0;
+
+                pixel.flux / (shot_photons * PI * pixel.radius * pixel.radius)
+            })
+            .collect();
+
+        film.set_photon_radiance(radiance);
+    }
+}
+
+/// Tiles find the visible points of the pass, batches shoot its photons.
+impl Integrator for PhotonMap {
+    // Photon mapping takes a single sample per pixel and pass
+    fn render_tile(
+        &self,
+        engine: &Engine,
+        settings: &RenderSettings,
+        film: &Film,
+        pass: u32,
+        tile_index: usize,
+        worker: &mut WorkerState,
+    ) -> usize {
+        let sampler = worker.sampler.as_mut();
+        let visible_points = &mut worker.visible_points;
+        visible_points.clear();
+
+        let samples = film.render_tile(
+            tile_index,
+            &mut worker.pixels,
+            &mut worker.accumulated,
+            |x, y, _| engine.trace_visible_point(settings, x, y, pass, sampler, visible_points),
+        );
+
+        self.add_visible_points(visible_points);
+        samples
+    }
+
+    fn batch_count(&self, _settings: &RenderSettings) -> usize {
+        self.batches.len()
+    }
+
+    fn start_batches(&self) {
+        self.build_grid();
+    }
+
+    fn render_batch(
+        &self,
+        engine: &Engine,
+        settings: &RenderSettings,
+        pass: u32,
+        batch: usize,
+        worker: &mut WorkerState,
+    ) {
+        let mut photons = self.batches[batch].lock().unwrap();
+        let grid = self.grid.read().unwrap();
+
+        let first = batch * PHOTON_BATCH;
+        let last = (first + PHOTON_BATCH).min(settings.photons_per_pass);
+        for photon in first..last {
+            engine.trace_photon(
+                settings,
+                photon,
+                pass,
+                &grid,
+                worker.sampler.as_mut(),
+                &mut photons,
+            );
+        }
+    }
+
+    fn end_pass(&self, settings: &RenderSettings, film: &Film, pass: u32) {
+        self.update_pixels(film, pass, settings.photons_per_pass);
+    }
+}
+
+impl Engine {
+    /// Follow the camera ray of pixel (`x`, `y`) through mirrors and refractions
+    /// until it picks the diffuse lobe of a surface, which is pushed to
+    /// `visible_points`. The returned sample holds the light found on the way and
+    /// the direct lighting of the visible point.
+    pub fn trace_visible_point(
+        &self,
+        settings: &RenderSettings,
+        x: usize,
+        y: usize,
+        pass: u32,
+        sampler: &mut dyn Sampler,
+        visible_points: &mut Vec<VisiblePoint>,
+    ) -> FilmPixel {
+        let mut pixel = FilmPixel::default();
+        sampler.start_sample(x, y, pass);
+
+        let mut ray = self.camera.create_ray(x, y, sampler);
+        let mut throughput = Vector3::repeat(1.);
+        let mut radiance = Vector3::zeros();
+
+        for bounce in 0..settings.max_depth {
+            let (record, object) = match self.get_closest_hit(
+                &ray,
+                self.camera.near_clipping_range,
+                self.camera.far_clipping_range,
+            ) {
+                Some(hit) => hit,
+                None => break,
+            };
+
+            // Emitters are only found through specular lobes, the ones seen from
+            // a diffuse surface are its direct lighting
+            let TextureMaterial { color, surface } = object.get_texture();
+            if let Some(emittance) = surface.emittance {
+                radiance += throughput.component_mul(&color) * emittance.ke;
+            }
+
+            let (lobe_weights, refracted_ray) = self.scattering_lobes(
+                &surface,
+                record.point,
+                record.normal,
+                &ray,
+                REFERENCE_WAVELENGTH,
+            );
+            let total_weight: Real = lobe_weights.iter().sum();
+            if total_weight <= 0. {
+                break;
+            }
+
+            let relative_normal = if record.normal.dot(&ray.direction) < 0. {
+                record.normal
+            } else {
+                -record.normal
+            };
+            let dimension = bounce_dimension(bounce);
+
+            let lobe = pick_lobe(
+                &lobe_weights,
+                total_weight,
+                sampler.get_1d(dimension + LOBE_DIMENSION),
+            );
+            match (lobe, refracted_ray) {
+                (0, _) => {
+                    // Dividing by the probability of the diffuse lobe leaves the
+                    // total weight, like in `trace_path`
+                    let weight = throughput.component_mul(&color) * total_weight;
+
+                    if bounce + 1 < settings.max_depth {
+                        let incident = self.sample_direct_lightning(
+                            settings,
+                            record.point,
+                            relative_normal,
+                            None,
+                            None,
+                            &Wavelengths::Rgb,
+                            sampler,
+                            dimension,
+                        ) + self.point_lights_lightning(
+                            record.point,
+                            relative_normal,
+                            None,
+                            &Wavelengths::Rgb,
+                        );
+                        radiance += weight.component_mul(&incident);
+                    }
+
+                    visible_points.push(VisiblePoint {
+                        pixel: y * self.canvas_width + x,
+                        point: record.point,
+                        normal: record.normal,
+                        wo: -ray.direction,
+                        weight: weight / (2. * PI),
+                    });
+                    break;
+                }
+                (1, _) => {
+                    throughput = throughput.component_mul(&color) * total_weight;
+                    ray = Ray::new(
+                        record.point + (relative_normal * RAY_EPSILON),
+                        (ray.direction
+                            - (2.0 * ray.direction.dot(&relative_normal) * relative_normal))
+                            .normalize(),
+                    );
+                }
+                (_, Some(refracted_ray)) => {
+                    throughput *= total_weight;
+                    ray = refracted_ray;
+                }
+                // The refraction lobe has no weight without a refracted ray
+                (_, None) => unreachable!(),
+            }
+        }
+
+        pixel.add_sample(radiance);
+        pixel
+    }
+
+    /// Shoot the `photon`-th photon of the pass `pass` from a light picked
+    /// uniformly, scattering it like `trace_path` scatters rays. Every diffuse
+    /// surface it bounces off after the first one adds its flux to the visible
+    /// points around, pushed to `photons` with their pixel offset.
+    pub fn trace_photon(
+        &self,
+        settings: &RenderSettings,
+        photon: usize,
+        pass: u32,
+        grid: &PhotonGrid,
+        sampler: &mut dyn Sampler,
+        photons: &mut Vec<(usize, Vector3<Real>)>,
+    ) {
+        let light_count = self.emitters.len() + self.lights.len();
+        if light_count == 0 {
+            return;
+        }
+
+        // Photons use the random streams of the row below the image
+        sampler.start_sample(photon, self.canvas_height, pass);
+
+        let dimension = bounce_dimension(0);
+        let pick = sampler.get_1d(dimension + LIGHT_PICK_DIMENSION);
+        let pick = ((pick * light_count as Real) as usize).min(light_count - 1);
+        let pick_pdf = 1. / light_count as Real;
+        let (u1, u2) = sampler.get_2d(dimension + BSDF_DIMENSION);
+
+        let (mut ray, mut flux) = if pick < self.emitters.len() {
+            let object = &self.objects[self.emitters[pick]];
+            let (point, normal) =
+                match object.sample_area(sampler.get_2d(dimension + LIGHT_DIMENSION)) {
+                    Some(sample) => sample,
+                    None => return,
+                };
+
+            // Cosine weighted direction on a side picked at random, the cosine of
+            // the emitted radiance cancels with its density of cos / (2 pi)
+            let (side, u1) = if u1 < 0.5 {
+                (normal, 2. * u1)
+            } else {
+                (-normal, 2. * u1 - 1.)
+            };
+            let (direction, _) = self.sample_hemisphere(side, (u1.sqrt(), u2));
+
+            let TextureMaterial { color, surface } = object.get_texture();
+            let emittance = color * surface.emittance.map(|e| e.ke).unwrap_or(0.);
+            (
+                Ray::new(point + side * RAY_EPSILON, direction),
+                emittance * 2. * PI * object.area() / pick_pdf,
+            )
+        } else {
+            let light = &self.lights[pick - self.emitters.len()];
+            (
+                Ray::new(light.position, uniform_sphere((u1, u2))),
+                light.intensity * light.color * 4. * PI / pick_pdf,
+            )
+        };
+
+        for bounce in 0..settings.max_depth {
+            let (record, object) = match self.get_closest_hit(
+                &ray,
+                self.camera.near_clipping_range,
+                self.camera.far_clipping_range,
+            ) {
+                Some(hit) => hit,
+                None => break,
+            };
+
+            // The first surface hit is lit directly, which the visible points
+            // already sampled
+            if bounce > 0 {
+                for visible_point in grid.gather(&record.point) {
+                    // Like the diffuse BSDF, light only reaches the side it comes from
+                    if visible_point.normal.dot(&visible_point.wo)
+                        * visible_point.normal.dot(&ray.direction)
+                        < 0.
+                    {
+                        photons.push((
+                            visible_point.pixel,
+                            flux.component_mul(&visible_point.weight),
+                        ));
+                    }
+                }
+            }
+
+            let TextureMaterial { color, surface } = object.get_texture();
+            let (lobe_weights, refracted_ray) = self.scattering_lobes(
+                &surface,
+                record.point,
+                record.normal,
+                &ray,
+                REFERENCE_WAVELENGTH,
+            );
+            let total_weight: Real = lobe_weights.iter().sum();
+            if total_weight <= 0. {
+                break;
+            }
+
+            let relative_normal = if record.normal.dot(&ray.direction) < 0. {
+                record.normal
+            } else {
+                -record.normal
+            };
+            let dimension = bounce_dimension(bounce + 1);
+
+            let lobe = pick_lobe(
+                &lobe_weights,
+                total_weight,
+                sampler.get_1d(dimension + LOBE_DIMENSION),
+            );
+            let (next_ray, scattering) = match (lobe, refracted_ray) {
+                (0, _) => {
+                    let (wi, cos_theta) = self.sample_hemisphere(
+                        relative_normal,
+                        sampler.get_2d(dimension + BSDF_DIMENSION),
+                    );
+                    (
+                        Ray::new(record.point + relative_normal * RAY_EPSILON, wi),
+                        color * total_weight * cos_theta,
+                    )
+                }
+                (1, _) => (
+                    Ray::new(
+                        record.point + (relative_normal * RAY_EPSILON),
+                        (ray.direction
+                            - (2.0 * ray.direction.dot(&relative_normal) * relative_normal))
+                            .normalize(),
+                    ),
+                    color * total_weight,
+                ),
+                (_, Some(refracted_ray)) => (refracted_ray, Vector3::repeat(total_weight)),
+                // The refraction lobe has no weight without a refracted ray
+                (_, None) => unreachable!(),
+            };
+
+            // Photons carry flux rather than a weight in [0, 1], so russian roulette
+            // looks at how much of it the bounce keeps
+            let survival = if bounce >= settings.min_depth {
+                scattering.max().min(1.)
+            } else {
+                1.
+            };
+            if survival < 1. && sampler.get_1d(dimension + RUSSIAN_ROULETTE_DIMENSION) >= survival {
+                break;
+            }
+
+            flux = flux.component_mul(&scattering) / survival;
+            ray = next_ray;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::rng::SampleRng;
+
+    fn visible_point(pixel: usize, point: Vector3<Real>) -> VisiblePoint {
+        VisiblePoint {
+            pixel,
+            point,
+            normal: Vector3::z(),
+            wo: Vector3::z(),
+            weight: Vector3::repeat(1.),
+        }
+    }
+
+    #[test]
+    fn grid_matches_brute_force() {
+        let mut rng = SampleRng::new(7, 0, 0, 0);
+        let map = PhotonMap::new(500, 0.1, 0);
+
+        // Radii already shrunk by different amounts, and pixels seeing nothing
+        let mut visible_points = vec![];
+        for (pixel, photon_pixel) in map.pixels.lock().unwrap().iter_mut().enumerate() {
+            photon_pixel.radius = rng.gen_range(0.01..0.2);
+            if rng.gen::<Real>() < 0.8 {
+                let point = Vector3::from_fn(|_, _| rng.gen_range(-1. ..1.));
+                visible_points.push(visible_point(pixel, point));
+            }
+        }
+        map.add_visible_points(&visible_points);
+        map.build_grid();
+
+        let pixels = map.pixels.lock().unwrap();
+        let grid = map.grid.read().unwrap();
+        let mut found = 0;
+        for _ in 0..2000 {
+            let point = Vector3::from_fn(|_, _| rng.gen_range(-1.1..1.1));
+
+            let mut gathered: Vec<usize> = grid.gather(&point).map(|vp| vp.pixel).collect();
+            let mut expected: Vec<usize> = visible_points
+                .iter()
+                .filter(|vp| {
+                    let radius = pixels[vp.pixel].radius;
+                    (vp.point - point).norm_squared() < radius * radius
+                })
+                .map(|vp| vp.pixel)
+                .collect();
+            gathered.sort_unstable();
+            expected.sort_unstable();
+
+            assert_eq!(gathered, expected);
+            found += expected.len();
+        }
+        assert!(found > 100);
+    }
+
+    #[test]
+    fn pixels_shrink_and_keep_their_flux_density() {
+        let (radius, photons_per_pass) = (0.1, 10);
+        let map = PhotonMap::new(4, radius, photons_per_pass);
+        let film = Film::new(2, 2);
+        let mut image = vec![Vector3::zeros(); 4];
+
+        // Pixel 1 finds 6 photons of flux 0.5, then 3 of flux 1, the others none.
+        // A share ALPHA of the new photons is kept and the disc shrinks so the
+        // density of the kept photons stays the same: 4 photons kept out of 6,
+        // then 6 out of 4 + 3.
+        let passes: [(usize, Real, Real, Real); 2] = [
+            (6, 0.5, 4., (4. / 6. as Real).sqrt()),
+            (3, 1., 6., (4. / 6. * 6. / 7. as Real).sqrt()),
+        ];
+        let fluxes = [3. * 4. / 6., (2. + 3.) * 6. / 7.];
+        for (pass, (found, photon_flux, photons, shrink)) in passes.into_iter().enumerate() {
+            map.batches[0]
+                .lock()
+                .unwrap()
+                .extend((0..found).map(|_| (1, Vector3::repeat(photon_flux))));
+            map.update_pixels(&film, pass as u32, photons_per_pass);
+
+            let pixels = map.pixels.lock().unwrap();
+            assert!((pixels[1].photons - photons).abs() < 1e-5);
+            assert!((pixels[1].radius - radius * shrink).abs() < 1e-6);
+            assert!((pixels[1].flux.x - fluxes[pass]).abs() < 1e-5);
+            assert_eq!(pixels[0].radius, radius);
+            assert_eq!(pixels[0].photons, 0.);
+            drop(pixels);
+
+            // Radiance is the flux over the disc, per photon shot
+            film.resolve_tile(0, &mut image);
+            let shot = ((pass + 1) * photons_per_pass) as Real;
+            let radiance = fluxes[pass] / (shot * PI * (radius * shrink).powi(2));
+            assert!((image[1].x - radiance).abs() < 1e-4 * radiance);
+            assert_eq!(image[0], Vector3::zeros());
+        }
+    }
+}
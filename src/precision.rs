@@ -0,0 +1,27 @@
+//! Scalar type of the renderer core, `f64` unless built with the `f32` feature.
+
+#[cfg(not(feature = "f32"))]
+pub type Real = f64;
+#[cfg(not(feature = "f32"))]
+pub use std::f64::consts;
+
+#[cfg(feature = "f32")]
+pub type Real = f32;
+#[cfg(feature = "f32")]
+pub use std::f32::consts;
+
+/// Below this determinant (or cosine for planes) a ray is considered parallel
+/// to the surface it is tested against. With f32 the determinant of grazing
+/// rays is mostly rounding error up to about 1e-6, so it must stay above that.
+#[cfg(not(feature = "f32"))]
+pub const HIT_EPSILON: Real = 1e-6;
+#[cfg(feature = "f32")]
+pub const HIT_EPSILON: Real = 1e-5;
+
+/// Offset applied to the origin of secondary rays so they don't hit the surface
+/// they start from. It must stay above the rounding error of intersection points,
+/// which is much larger with f32 for scenes spanning a few units.
+#[cfg(not(feature = "f32"))]
+pub const RAY_EPSILON: Real = 1e-4;
+#[cfg(feature = "f32")]
+pub const RAY_EPSILON: Real = 1e-3;
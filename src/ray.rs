@@ -1,17 +1,19 @@
 extern crate nalgebra;
 use nalgebra::Vector3;
 
+use crate::precision::Real;
+
 pub struct Ray {
-    pub origin: Vector3<f64>,
-    pub direction: Vector3<f64>,
+    pub origin: Vector3<Real>,
+    pub direction: Vector3<Real>,
 }
 
 impl Ray {
-    pub fn new(origin: Vector3<f64>, direction: Vector3<f64>) -> Self {
+    pub fn new(origin: Vector3<Real>, direction: Vector3<Real>) -> Self {
         Self { origin, direction }
     }
 
-    pub fn at(&self, t: f64) -> Vector3<f64> {
+    pub fn at(&self, t: Real) -> Vector3<Real> {
         return self.origin + t * self.direction;
     }
 }
@@ -0,0 +1,131 @@
+//! Deterministic random numbers, one independent stream per pixel sample so an
+//! image only depends on the seed and not on how the work was split between threads.
+
+use rand::{Error, RngCore};
+
+use crate::precision::Real;
+
+/// PCG32 (XSH RR variant). The algorithm is fixed here rather than taken from
+/// `rand::rngs::StdRng`, whose output may change between versions of the crate.
+#[derive(Clone, Debug)]
+pub struct SampleRng {
+    state: u64,
+    increment: u64,
+}
+
+const MULTIPLIER: u64 = 6364136223846793005;
+
+impl SampleRng {
+    /// Stream of the `sample`-th sample of pixel (`x`, `y`).
+    pub fn new(seed: u64, x: usize, y: usize, sample: u32) -> Self {
+        let stream = mix(pixel_hash(seed, x, y), sample as u64);
+
+        let mut rng = Self {
+            state: 0,
+            // The increment must be odd
+            increment: (splitmix64(stream) << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(stream);
+        rng.next_u32();
+        rng
+    }
+
+    /// Stream seeded by one dimension of a sample, for decisions drawing as many
+    /// numbers as they need.
+    pub fn from_sample(u: Real) -> Self {
+        Self::new((u * u32::MAX as Real) as u64, 0, 0, 0)
+    }
+}
+
+impl RngCore for SampleRng {
+    fn next_u32(&mut self) -> u32 {
+        let state = self.state;
+        self.state = state.wrapping_mul(MULTIPLIER).wrapping_add(self.increment);
+
+        let xorshifted = (((state >> 18) ^ state) >> 27) as u32;
+        xorshifted.rotate_right((state >> 59) as u32)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.next_u32() as u64) << 32 | self.next_u32() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            chunk.copy_from_slice(&self.next_u32().to_le_bytes()[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Hash identifying pixel (`x`, `y`) for a given seed.
+pub fn pixel_hash(seed: u64, x: usize, y: usize) -> u64 {
+    mix(mix(splitmix64(seed), x as u64), y as u64)
+}
+
+/// Combine a hash with another value into a new hash.
+pub fn mix(hash: u64, value: u64) -> u64 {
+    splitmix64(hash ^ value)
+}
+
+/// Bijective 64 bit mix, so close inputs (neighbouring pixels) give unrelated outputs.
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+
+    fn draws(mut rng: SampleRng) -> Vec<u32> {
+        (0..64).map(|_| rng.gen()).collect()
+    }
+
+    #[test]
+    fn streams_only_depend_on_their_sample() {
+        let stream = draws(SampleRng::new(7, 3, 5, 2));
+        assert_eq!(draws(SampleRng::new(7, 3, 5, 2)), stream);
+
+        // Streams created in any order or on other threads are the same
+        let from_thread = std::thread::spawn(|| draws(SampleRng::new(7, 3, 5, 2)));
+        assert_eq!(from_thread.join().unwrap(), stream);
+
+        // Changing any coordinate gives another stream
+        for other in [
+            SampleRng::new(8, 3, 5, 2),
+            SampleRng::new(7, 4, 5, 2),
+            SampleRng::new(7, 3, 6, 2),
+            SampleRng::new(7, 3, 5, 3),
+            SampleRng::new(7, 5, 3, 2),
+        ] {
+            assert_ne!(draws(other), stream);
+        }
+
+        assert_eq!(
+            draws(SampleRng::from_sample(0.25)),
+            draws(SampleRng::from_sample(0.25))
+        );
+    }
+
+    #[test]
+    fn floats_are_in_unit_interval() {
+        let mut rng = SampleRng::new(0, 0, 0, 0);
+        let mut sum = 0.;
+        for _ in 0..10000 {
+            let u: Real = rng.gen();
+            assert!((0. ..1.).contains(&u));
+            sum += u;
+        }
+        assert!((sum / 10000. - 0.5).abs() < 0.02);
+    }
+}
@@ -0,0 +1,381 @@
+//! Sample points driving every random decision of a path. Each decision reads a
+//! fixed dimension of the sample, so low discrepancy sequences stay well
+//! distributed dimension by dimension instead of mixing unrelated decisions.
+
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::precision::Real;
+use crate::rng::{mix, pixel_hash, SampleRng};
+
+/// Dimensions of the jitter of the primary ray inside its pixel.
+pub const CAMERA_DIMENSION: u32 = 0;
+/// Dimension of the wavelengths of a spectral path.
+pub const WAVELENGTH_DIMENSION: u32 = CAMERA_DIMENSION + 2;
+/// Dimensions reserved for each bounce of a path.
+const BOUNCE_DIMENSIONS: u32 = 9;
+
+/// First dimension of the `bounce`-th bounce of a path.
+pub fn bounce_dimension(bounce: u32) -> u32 {
+    WAVELENGTH_DIMENSION + 1 + bounce * BOUNCE_DIMENSIONS
+}
+
+#[derive(clap::ArgEnum, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+impl SamplerKind {
+    pub fn create(self, seed: u64) -> Box<dyn Sampler> {
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler {
+                seed,
+                rng: SampleRng::new(seed, 0, 0, 0),
+            }),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::new(seed)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => Box::new(SobolSampler::new(seed)),
+        }
+    }
+}
+
+pub trait Sampler {
+    /// Move to the `index`-th sample of pixel (`x`, `y`).
+    fn start_sample(&mut self, x: usize, y: usize, index: u32);
+
+    /// Coordinate `dimension` of the current sample, in [0, 1).
+    fn get_1d(&mut self, dimension: u32) -> Real;
+
+    /// Coordinates `dimension` and `dimension + 1` of the current sample.
+    fn get_2d(&mut self, dimension: u32) -> (Real, Real);
+}
+
+/// Uniform random numbers, dimensions are ignored.
+pub struct IndependentSampler {
+    seed: u64,
+    rng: SampleRng,
+}
+
+impl Sampler for IndependentSampler {
+    fn start_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.rng = SampleRng::new(self.seed, x, y, index);
+    }
+
+    fn get_1d(&mut self, _dimension: u32) -> Real {
+        self.rng.gen()
+    }
+
+    fn get_2d(&mut self, _dimension: u32) -> (Real, Real) {
+        (self.rng.gen(), self.rng.gen())
+    }
+}
+
+// Number of strata along each axis of a 2D stratified dimension
+const STRATA_SIDE: u32 = 4;
+const STRATA: u32 = STRATA_SIDE * STRATA_SIDE;
+
+/// Jittered sampling: every run of `STRATA` consecutive samples of a pixel puts
+/// one sample in each stratum, visited in a random order per dimension.
+pub struct StratifiedSampler {
+    seed: u64,
+    pixel: u64,
+    index: u32,
+}
+
+impl StratifiedSampler {
+    fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pixel: 0,
+            index: 0,
+        }
+    }
+
+    fn stratum(&self, dimension: u32) -> u32 {
+        let run = mix(
+            mix(self.pixel, dimension as u64),
+            (self.index / STRATA) as u64,
+        );
+        permutation_element(self.index % STRATA, STRATA, run as u32)
+    }
+
+    fn jitter(&self, dimension: u32) -> Real {
+        hash_to_unit(mix(mix(self.pixel, self.index as u64), dimension as u64))
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.pixel = pixel_hash(self.seed, x, y);
+        self.index = index;
+    }
+
+    fn get_1d(&mut self, dimension: u32) -> Real {
+        (self.stratum(dimension) as Real + self.jitter(dimension)) / STRATA as Real
+    }
+
+    fn get_2d(&mut self, dimension: u32) -> (Real, Real) {
+        let stratum = self.stratum(dimension);
+        (
+            ((stratum % STRATA_SIDE) as Real + self.jitter(dimension)) / STRATA_SIDE as Real,
+            ((stratum / STRATA_SIDE) as Real + self.jitter(dimension + 1)) / STRATA_SIDE as Real,
+        )
+    }
+}
+
+// Bases of the Halton sequence, dimensions past the table fall back to random numbers
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+/// Halton sequence, randomized per pixel by a random toroidal shift of each
+/// dimension (Cranley-Patterson rotation).
+pub struct HaltonSampler {
+    seed: u64,
+    pixel: u64,
+    index: u32,
+}
+
+impl HaltonSampler {
+    fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pixel: 0,
+            index: 0,
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.pixel = pixel_hash(self.seed, x, y);
+        self.index = index;
+    }
+
+    fn get_1d(&mut self, dimension: u32) -> Real {
+        let base = match PRIMES.get(dimension as usize) {
+            Some(&base) => base,
+            None => return hash_to_unit(mix(mix(self.pixel, self.index as u64), dimension as u64)),
+        };
+
+        let value =
+            radical_inverse(base, self.index) + hash_to_unit(mix(self.pixel, dimension as u64));
+        (value - value.floor()).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self, dimension: u32) -> (Real, Real) {
+        (self.get_1d(dimension), self.get_1d(dimension + 1))
+    }
+}
+
+/// First two dimensions of the Sobol sequence with Owen scrambling, every pair
+/// of dimensions gets its own scramble and shuffled sample order so the pairs
+/// are not correlated with each other (Burley, "Practical Hash-based Owen
+/// Scrambling", 2020).
+pub struct SobolSampler {
+    seed: u64,
+    pixel: u64,
+    index: u32,
+}
+
+impl SobolSampler {
+    fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pixel: 0,
+            index: 0,
+        }
+    }
+
+    fn shuffled_index(&self, dimension: u32) -> u32 {
+        nested_uniform_scramble(self.index, mix(self.pixel, dimension as u64) as u32)
+    }
+
+    fn scramble(&self, value: u32, dimension: u32, axis: u64) -> Real {
+        let seed = mix(mix(self.pixel, dimension as u64), axis);
+        to_unit(nested_uniform_scramble(value, seed as u32))
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.pixel = pixel_hash(self.seed, x, y);
+        self.index = index;
+    }
+
+    fn get_1d(&mut self, dimension: u32) -> Real {
+        let index = self.shuffled_index(dimension);
+        self.scramble(index.reverse_bits(), dimension, 0)
+    }
+
+    fn get_2d(&mut self, dimension: u32) -> (Real, Real) {
+        let index = self.shuffled_index(dimension);
+        (
+            self.scramble(index.reverse_bits(), dimension, 0),
+            self.scramble(sobol_second_dimension(index), dimension, 1),
+        )
+    }
+}
+
+// Largest value below one
+const ONE_MINUS_EPSILON: Real = 1. - Real::EPSILON / 2.;
+
+/// Map 32 bits, seen as a fraction, to [0, 1). Rounding could reach one with f32.
+fn to_unit(bits: u32) -> Real {
+    (bits as Real / (1u64 << 32) as Real).min(ONE_MINUS_EPSILON)
+}
+
+fn hash_to_unit(hash: u64) -> Real {
+    to_unit((hash >> 32) as u32)
+}
+
+fn radical_inverse(base: u32, mut index: u32) -> Real {
+    let inv_base = 1. / base as Real;
+    let mut inv_base_n = 1.;
+    let mut reversed: u64 = 0;
+
+    while index > 0 {
+        reversed = reversed * base as u64 + (index % base) as u64;
+        index /= base;
+        inv_base_n *= inv_base;
+    }
+
+    reversed as Real * inv_base_n
+}
+
+/// Second dimension of the Sobol sequence, its direction numbers follow
+/// v[k] = v[k-1] ^ (v[k-1] >> 1).
+fn sobol_second_dimension(mut index: u32) -> u32 {
+    let mut direction = 1 << 31;
+    let mut value = 0;
+
+    while index != 0 {
+        if index & 1 != 0 {
+            value ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+
+    value
+}
+
+/// Owen scrambling of the bits of `value`, seen as a fraction in [0, 1).
+fn nested_uniform_scramble(value: u32, seed: u32) -> u32 {
+    laine_karras_permutation(value.reverse_bits(), seed).reverse_bits()
+}
+
+// Hash where each bit only depends on the bits below it
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+/// Element `index` of a random permutation of 0..`length` picked by `seed`
+/// (Kensler, "Correlated Multi-Jittered Sampling", 2013).
+fn permutation_element(mut index: u32, length: u32, seed: u32) -> u32 {
+    let mut mask = length - 1;
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+
+    loop {
+        index ^= seed;
+        index = index.wrapping_mul(0xe170893d);
+        index ^= seed >> 16;
+        index ^= (index & mask) >> 4;
+        index ^= seed >> 8;
+        index = index.wrapping_mul(0x0929eb3f);
+        index ^= seed >> 23;
+        index ^= (index & mask) >> 1;
+        index = index.wrapping_mul(1 | seed >> 27);
+        index = index.wrapping_mul(0x6935fa69);
+        index ^= (index & mask) >> 11;
+        index = index.wrapping_mul(0x74dcb303);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0x9e501cc3);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0xc860a3df);
+        index &= mask;
+        index ^= index >> 5;
+
+        if index < length {
+            return index.wrapping_add(seed) % length;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [SamplerKind; 4] = [
+        SamplerKind::Independent,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+    ];
+
+    #[test]
+    fn samples_are_in_unit_interval() {
+        for kind in KINDS {
+            let mut sampler = kind.create(7);
+            for (x, y) in [(0, 0), (3, 5), (1000, 17)] {
+                for index in (0..200).chain([u32::MAX - 1, u32::MAX]) {
+                    sampler.start_sample(x, y, index);
+                    // Past the end of the Halton table too
+                    for dimension in 0..80 {
+                        let value = sampler.get_1d(dimension);
+                        let (u, v) = sampler.get_2d(dimension);
+                        for value in [value, u, v] {
+                            assert!((0. ..1.).contains(&value), "{:?}: {}", kind, value);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn runs_of_samples_are_stratified() {
+        for kind in [SamplerKind::Stratified, SamplerKind::Sobol] {
+            let mut sampler = kind.create(7);
+            for (x, y) in [(0, 0), (3, 5), (1000, 17)] {
+                // Every aligned run of `STRATA` samples covers all the strata
+                for run in 0..3 {
+                    for dimension in [0, 4, 21] {
+                        let mut strata_1d = [0; STRATA as usize];
+                        let mut strata_2d = [0; STRATA as usize];
+                        for index in run * STRATA..(run + 1) * STRATA {
+                            sampler.start_sample(x, y, index);
+
+                            let value = sampler.get_1d(dimension);
+                            strata_1d[(value * STRATA as Real) as usize] += 1;
+
+                            let (u, v) = sampler.get_2d(dimension);
+                            let side = STRATA_SIDE as Real;
+                            let stratum = (u * side) as u32 + (v * side) as u32 * STRATA_SIDE;
+                            strata_2d[stratum as usize] += 1;
+                        }
+
+                        assert_eq!(strata_1d, [1; STRATA as usize], "{:?}", kind);
+                        assert_eq!(strata_2d, [1; STRATA as usize], "{:?}", kind);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -3,8 +3,11 @@ use serde::Deserialize;
 use crate::{
     camera::Camera,
     light::PointLight,
-    mesh::MeshConfig,
+    medium::Medium,
+    mesh::{InstanceConfig, MeshConfig, ModelConfig},
     objects::{Plane, Sphere, Triangle},
+    sampler::SamplerKind,
+    volume::VolumeConfig,
 };
 
 #[derive(Debug, Deserialize)]
@@ -19,4 +22,20 @@ pub struct Scene {
     pub planes: Vec<Plane>,
     #[serde(default = "Vec::new")]
     pub meshes: Vec<MeshConfig>,
+    // Materials of the obj models used by instances
+    #[serde(default = "Vec::new")]
+    pub models: Vec<ModelConfig>,
+    #[serde(default = "Vec::new")]
+    pub instances: Vec<InstanceConfig>,
+    #[serde(default = "Vec::new")]
+    pub volumes: Vec<VolumeConfig>,
+    #[serde(default)]
+    pub sampler: Option<SamplerKind>,
+    #[serde(default)]
+    pub min_depth: Option<u32>,
+    #[serde(default)]
+    pub max_depth: Option<u32>,
+    // Medium filling the scene around the objects
+    #[serde(default)]
+    pub medium: Option<Medium>,
 }
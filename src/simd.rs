@@ -0,0 +1,398 @@
+extern crate nalgebra;
+
+use nalgebra::Vector3;
+
+use crate::precision::{Real, HIT_EPSILON};
+use crate::ray::Ray;
+
+/// Number of triangles tested at once, as many as fit in an AVX register.
+#[cfg(not(feature = "f32"))]
+pub const LANES: usize = 4;
+#[cfg(feature = "f32")]
+pub const LANES: usize = 8;
+
+type Lanes = [Real; LANES];
+
+/// Up to `LANES` triangles stored as structure of arrays, so one ray can be
+/// tested against all of them at once. Unused lanes hold degenerate triangles
+/// that never report a hit.
+#[derive(Clone, Debug)]
+pub struct TrianglePacket {
+    v0: [Lanes; 3],
+    v0v1: [Lanes; 3],
+    v0v2: [Lanes; 3],
+    pub triangles: [usize; LANES],
+    pub count: usize,
+}
+
+impl TrianglePacket {
+    /// Gather up to `LANES` triangles of an indexed mesh, `triangles` are positions
+    /// in `indices`.
+    pub fn gather(vertices: &[Vector3<Real>], indices: &[[u32; 3]], triangles: &[usize]) -> Self {
+        assert!(triangles.len() <= LANES);
+
+        let mut packet = Self {
+            v0: [[0.; LANES]; 3],
+            v0v1: [[0.; LANES]; 3],
+            v0v2: [[0.; LANES]; 3],
+            triangles: [0; LANES],
+            count: triangles.len(),
+        };
+
+        for (lane, &triangle) in triangles.iter().enumerate() {
+            let [i0, i1, i2] = indices[triangle];
+            let v0 = vertices[i0 as usize];
+            let v0v1 = vertices[i1 as usize] - v0;
+            let v0v2 = vertices[i2 as usize] - v0;
+
+            for axis in 0..3 {
+                packet.v0[axis][lane] = v0[axis];
+                packet.v0v1[axis][lane] = v0v1[axis];
+                packet.v0v2[axis][lane] = v0v2[axis];
+            }
+            packet.triangles[lane] = triangle;
+        }
+
+        packet
+    }
+
+    /// Möller-Trumbore on every lane, returns the lane and distance of the nearest hit.
+    pub fn intersects(
+        &self,
+        ray: &Ray,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
+    ) -> Option<(usize, Real)> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx") {
+                // Safety: AVX support was checked just above
+                let t =
+                    unsafe { avx::distances(self, ray, near_clipping_range, far_clipping_range) };
+                return self.nearest(&t);
+            }
+        }
+
+        let t = self.distances_scalar(ray, near_clipping_range, far_clipping_range);
+        self.nearest(&t)
+    }
+
+    fn nearest(&self, t: &Lanes) -> Option<(usize, Real)> {
+        let mut nearest = None;
+
+        for (lane, &t) in t.iter().enumerate().take(self.count) {
+            if t.is_finite() && nearest.is_none_or(|(_, min_t)| t < min_t) {
+                nearest = Some((lane, t));
+            }
+        }
+
+        nearest
+    }
+
+    /// Distance of the hit on every lane, infinity on a miss.
+    fn distances_scalar(
+        &self,
+        ray: &Ray,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
+    ) -> Lanes {
+        let mut t = [Real::INFINITY; LANES];
+
+        for (lane, t) in t.iter_mut().enumerate().take(self.count) {
+            let v0 = Vector3::new(self.v0[0][lane], self.v0[1][lane], self.v0[2][lane]);
+            let v0v1 = Vector3::new(self.v0v1[0][lane], self.v0v1[1][lane], self.v0v1[2][lane]);
+            let v0v2 = Vector3::new(self.v0v2[0][lane], self.v0v2[1][lane], self.v0v2[2][lane]);
+
+            let p = ray.direction.cross(&v0v2);
+            let det = v0v1.dot(&p);
+
+            if det > -HIT_EPSILON && det < HIT_EPSILON {
+                continue; // Ray is parallel to triangle.
+            }
+
+            let inv_det = 1.0 / det;
+            let s = ray.origin - v0;
+            let u = s.dot(&p) * inv_det;
+            let q = s.cross(&v0v1);
+            let v = ray.direction.dot(&q) * inv_det;
+            let hit_t = v0v2.dot(&q) * inv_det;
+
+            if (0.0..=1.0).contains(&u)
+                && v >= 0.0
+                && u + v <= 1.0
+                && hit_t >= near_clipping_range
+                && hit_t <= far_clipping_range
+            {
+                *t = hit_t;
+            }
+        }
+
+        t
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx {
+    use std::arch::x86_64::*;
+
+    use super::{Lanes, TrianglePacket, LANES};
+    use crate::precision::{Real, HIT_EPSILON};
+    use crate::ray::Ray;
+
+    #[cfg(not(feature = "f32"))]
+    type V = __m256d;
+    #[cfg(feature = "f32")]
+    type V = __m256;
+
+    // Thin wrappers picking the f64 (pd) or f32 (ps) flavour of each intrinsic
+    macro_rules! wrap {
+        ($($name:ident($($arg:ident: $ty:ty),*) => $pd:expr, $ps:expr;)*) => {$(
+            #[inline]
+            #[target_feature(enable = "avx")]
+            unsafe fn $name($($arg: $ty),*) -> V {
+                #[cfg(not(feature = "f32"))]
+                return ($pd)($($arg),*);
+                #[cfg(feature = "f32")]
+                return ($ps)($($arg),*);
+            }
+        )*};
+    }
+
+    wrap! {
+        add(a: V, b: V) => _mm256_add_pd, _mm256_add_ps;
+        sub(a: V, b: V) => _mm256_sub_pd, _mm256_sub_ps;
+        mul(a: V, b: V) => _mm256_mul_pd, _mm256_mul_ps;
+        div(a: V, b: V) => _mm256_div_pd, _mm256_div_ps;
+        and(a: V, b: V) => _mm256_and_pd, _mm256_and_ps;
+        andnot(a: V, b: V) => _mm256_andnot_pd, _mm256_andnot_ps;
+        blend(a: V, b: V, mask: V) => _mm256_blendv_pd, _mm256_blendv_ps;
+        splat(x: Real) => _mm256_set1_pd, _mm256_set1_ps;
+        ge(a: V, b: V) => _mm256_cmp_pd::<_CMP_GE_OQ>, _mm256_cmp_ps::<_CMP_GE_OQ>;
+        le(a: V, b: V) => _mm256_cmp_pd::<_CMP_LE_OQ>, _mm256_cmp_ps::<_CMP_LE_OQ>;
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx")]
+    unsafe fn load(lanes: &Lanes) -> V {
+        #[cfg(not(feature = "f32"))]
+        return _mm256_loadu_pd(lanes.as_ptr());
+        #[cfg(feature = "f32")]
+        return _mm256_loadu_ps(lanes.as_ptr());
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx")]
+    unsafe fn store(v: V) -> Lanes {
+        let mut lanes = [0.; LANES];
+        #[cfg(not(feature = "f32"))]
+        _mm256_storeu_pd(lanes.as_mut_ptr(), v);
+        #[cfg(feature = "f32")]
+        _mm256_storeu_ps(lanes.as_mut_ptr(), v);
+        lanes
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx")]
+    unsafe fn cross(a: [V; 3], b: [V; 3]) -> [V; 3] {
+        [
+            sub(mul(a[1], b[2]), mul(a[2], b[1])),
+            sub(mul(a[2], b[0]), mul(a[0], b[2])),
+            sub(mul(a[0], b[1]), mul(a[1], b[0])),
+        ]
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx")]
+    unsafe fn dot(a: [V; 3], b: [V; 3]) -> V {
+        add(add(mul(a[0], b[0]), mul(a[1], b[1])), mul(a[2], b[2]))
+    }
+
+    /// Same as `TrianglePacket::distances_scalar`, all lanes at once.
+    #[target_feature(enable = "avx")]
+    pub unsafe fn distances(
+        packet: &TrianglePacket,
+        ray: &Ray,
+        near_clipping_range: Real,
+        far_clipping_range: Real,
+    ) -> Lanes {
+        let origin = [
+            splat(ray.origin.x),
+            splat(ray.origin.y),
+            splat(ray.origin.z),
+        ];
+        let direction = [
+            splat(ray.direction.x),
+            splat(ray.direction.y),
+            splat(ray.direction.z),
+        ];
+        let v0 = packet.v0.map(|lanes| load(&lanes));
+        let v0v1 = packet.v0v1.map(|lanes| load(&lanes));
+        let v0v2 = packet.v0v2.map(|lanes| load(&lanes));
+
+        let zero = splat(0.);
+        let one = splat(1.);
+
+        let p = cross(direction, v0v2);
+        let det = dot(v0v1, p);
+        // |det| >= HIT_EPSILON, the sign bit is cleared with an and-not
+        let abs_det = andnot(splat(-0.), det);
+        let mut mask = ge(abs_det, splat(HIT_EPSILON));
+
+        let inv_det = div(one, det);
+        let s = [
+            sub(origin[0], v0[0]),
+            sub(origin[1], v0[1]),
+            sub(origin[2], v0[2]),
+        ];
+        let u = mul(dot(s, p), inv_det);
+        let q = cross(s, v0v1);
+        let v = mul(dot(direction, q), inv_det);
+        let t = mul(dot(v0v2, q), inv_det);
+
+        mask = and(mask, ge(u, zero));
+        mask = and(mask, le(u, one));
+        mask = and(mask, ge(v, zero));
+        mask = and(mask, le(add(u, v), one));
+        mask = and(mask, ge(t, splat(near_clipping_range)));
+        mask = and(mask, le(t, splat(far_clipping_range)));
+
+        store(blend(splat(Real::INFINITY), t, mask))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use nalgebra::Rotation3;
+
+    use super::*;
+    use crate::objects::{ObjectsTrait, Triangle};
+    use crate::precision::{consts::PI, RAY_EPSILON};
+    use crate::rng::SampleRng;
+
+    fn random_vector(rng: &mut SampleRng, scale: Real) -> Vector3<Real> {
+        Vector3::new(rng.gen(), rng.gen(), rng.gen()).map(|x: Real| (x - 0.5) * scale)
+    }
+
+    #[test]
+    fn packet_matches_scalar_moller_trumbore() {
+        let mut rng = SampleRng::new(3, 0, 0, 0);
+        let (near, far) = (1e-4, 1e3);
+        let mut hits = 0;
+
+        for _ in 0..2000 {
+            let count = rng.gen_range(1..=LANES);
+            let vertices: Vec<Vector3<Real>> = (0..3 * count)
+                .map(|_| random_vector(&mut rng, 2.))
+                .collect();
+            let indices: Vec<[u32; 3]> = (0..count as u32)
+                .map(|i| [3 * i, 3 * i + 1, 3 * i + 2])
+                .collect();
+            let packet = TrianglePacket::gather(&vertices, &indices, &Vec::from_iter(0..count));
+
+            let origin = random_vector(&mut rng, 6.);
+            let ray = Ray::new(origin, (random_vector(&mut rng, 1.) - origin).normalize());
+
+            #[cfg(target_arch = "x86_64")]
+            if is_x86_feature_detected!("avx") {
+                // Safety: AVX support was checked just above
+                let simd = unsafe { avx::distances(&packet, &ray, near, far) };
+                let scalar = packet.distances_scalar(&ray, near, far);
+                for (simd, scalar) in simd.iter().zip(&scalar).take(count) {
+                    assert!(simd == scalar || (simd - scalar).abs() <= 1e-4 * scalar.abs());
+                }
+            }
+
+            let expected = (0..count)
+                .filter_map(|i| {
+                    let [v0, v1, v2] = indices[i].map(|v| vertices[v as usize]);
+                    let triangle = Triangle {
+                        v0,
+                        v1,
+                        v2,
+                        textmat: Default::default(),
+                    };
+                    Some((i, triangle.intersects(&ray, near, far)?.t))
+                })
+                .min_by(|(_, t0), (_, t1)| t0.total_cmp(t1));
+
+            match (packet.intersects(&ray, near, far), expected) {
+                (Some((lane, t)), Some((triangle, expected))) => {
+                    assert_eq!(packet.triangles[lane], triangle);
+                    assert!((t - expected).abs() <= 1e-4 * expected);
+                    hits += 1;
+                }
+                (nearest, expected) => assert_eq!(nearest.is_some(), expected.is_some()),
+            }
+        }
+        assert!(hits > 200);
+    }
+
+    #[test]
+    fn grazing_rays_hit_on_the_triangle_plane() {
+        let mut rng = SampleRng::new(4, 0, 0, 0);
+        let (near, far) = (1e-4, 1e3);
+
+        for _ in 0..1000 {
+            // Right triangle with sides of 2 in the z = 0 plane, moved far from the origin
+            let angles = Vector3::new(rng.gen(), rng.gen(), rng.gen()) * 2. * PI;
+            let rotation = Rotation3::from_euler_angles(angles.x, angles.y, angles.z);
+            let translation = random_vector(&mut rng, 20.);
+            let to_world = |local: Vector3<Real>| rotation * local + translation;
+
+            let vertices = vec![
+                to_world(Vector3::zeros()),
+                to_world(Vector3::new(2., 0., 0.)),
+                to_world(Vector3::new(0., 2., 0.)),
+            ];
+            let packet = TrianglePacket::gather(&vertices, &[[0, 1, 2]], &[0]);
+            let triangle = Triangle {
+                v0: vertices[0],
+                v1: vertices[1],
+                v2: vertices[2],
+                textmat: Default::default(),
+            };
+            let normal = rotation * Vector3::z();
+
+            // Parallel to the triangle, slightly above it
+            let ray = Ray::new(
+                to_world(Vector3::new(-1., 0.5, 0.01)),
+                rotation * Vector3::x(),
+            );
+            assert!(triangle.intersects(&ray, near, far).is_none());
+            assert!(packet.intersects(&ray, near, far).is_none());
+
+            // Aimed at the inside of the triangle with a slope going down to zero
+            for slope in [1e-1, 1e-2, 1e-3, 1e-4, 1e-5, 1e-6, 1e-7] {
+                let origin = to_world(Vector3::new(-1., 0.5, 1.5 * slope));
+                let direction = rotation * Vector3::new(1.5, 0., -1.5 * slope).normalize();
+                let ray = Ray::new(origin, direction);
+
+                let hits = [
+                    triangle.intersects(&ray, near, far).map(|hit| hit.t),
+                    packet.intersects(&ray, near, far).map(|(_, t)| t),
+                ];
+                for t in hits.into_iter().flatten() {
+                    // Secondary rays leave from the hit point, off by RAY_EPSILON along
+                    // the normal, so it must not be further than that from the plane
+                    let height = (ray.at(t) - vertices[0]).dot(&normal);
+                    assert!(
+                        height.abs() < RAY_EPSILON,
+                        "slope {}: height {}",
+                        slope,
+                        height
+                    );
+
+                    // The distance gets unstable as the ray turns parallel, such hits
+                    // must be rejected instead of landing anywhere along the ray
+                    let error = (ray.at(t) - to_world(Vector3::new(0.5, 0.5, 0.))).norm();
+                    assert!(error < 0.1, "slope {}: hit {} away", slope, error);
+                }
+                if slope >= 1e-2 {
+                    assert!(hits.iter().all(Option::is_some));
+                }
+            }
+        }
+    }
+}
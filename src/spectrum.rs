@@ -0,0 +1,256 @@
+//! Spectral rendering: each path carries three wavelengths instead of the red,
+//! green and blue channels, the three slots of its radiance vectors holding the
+//! values at those wavelengths. RGB colors of the scene are turned into spectra
+//! (Smits, "An RGB to Spectrum Conversion for Reflectances", 1999) and the
+//! radiance of a path is brought back to RGB through the CIE color matching
+//! functions (analytic fit of Wyman et al., 2013).
+
+use std::sync::OnceLock;
+
+use nalgebra::{Matrix3, Vector3};
+
+use crate::engine::RenderSettings;
+use crate::medium::Medium;
+use crate::precision::Real;
+use crate::sampler::{Sampler, WAVELENGTH_DIMENSION};
+
+/// Visible range, in nanometers.
+pub const MIN_WAVELENGTH: Real = 380.;
+pub const MAX_WAVELENGTH: Real = 720.;
+/// Wavelength at which RGB renders evaluate dispersive indices of refraction
+/// (sodium D line).
+pub const REFERENCE_WAVELENGTH: Real = 589.3;
+
+// Smits' spectra, sampled at the center of 10 bins spanning the visible range
+const WHITE: [Real; 10] = [1., 1., 0.9999, 0.9993, 0.9992, 0.9998, 1., 1., 1., 1.];
+const CYAN: [Real; 10] = [
+    0.971, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0., 0., 0.,
+];
+const MAGENTA: [Real; 10] = [1., 1., 0.9685, 0.2229, 0., 0.0458, 0.8369, 1., 1., 0.9959];
+const YELLOW: [Real; 10] = [
+    0.0001, 0., 0.1088, 0.6651, 1., 1., 0.9996, 0.9586, 0.9685, 0.984,
+];
+const RED: [Real; 10] = [
+    0.1012, 0.0515, 0., 0., 0., 0., 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const GREEN: [Real; 10] = [0., 0., 0.0273, 0.7937, 1., 0.9418, 0.1719, 0., 0., 0.0025];
+const BLUE: [Real; 10] = [
+    1., 1., 0.8916, 0.3323, 0., 0., 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+/// Wavelengths carried by a path.
+#[derive(Copy, Clone, Debug)]
+pub enum Wavelengths {
+    // The slots are the red, green and blue channels
+    Rgb,
+    // The slots are the values at `lambda`, in nanometers. After a dispersive
+    // refraction only the first one, the hero wavelength, is still followed.
+    Spectral {
+        lambda: Vector3<Real>,
+        hero_only: bool,
+    },
+}
+
+impl Wavelengths {
+    /// Wavelengths of a new path, drawn from `sampler` in spectral renders.
+    pub fn for_path(settings: &RenderSettings, sampler: &mut dyn Sampler) -> Self {
+        if settings.spectral {
+            Self::sample(sampler.get_1d(WAVELENGTH_DIMENSION))
+        } else {
+            Wavelengths::Rgb
+        }
+    }
+
+    /// Wavelengths evenly spaced over the visible range, the first one at `u`.
+    pub fn sample(u: Real) -> Self {
+        let range = MAX_WAVELENGTH - MIN_WAVELENGTH;
+        let lambda = Vector3::from_fn(|i, _| {
+            let offset = u + i as Real / 3.;
+            MIN_WAVELENGTH + (offset - offset.floor()) * range
+        });
+
+        Wavelengths::Spectral {
+            lambda,
+            hero_only: false,
+        }
+    }
+
+    /// Wavelength deciding the paths of light, whose index of refraction is used.
+    pub fn hero(&self) -> Real {
+        match self {
+            Wavelengths::Rgb => REFERENCE_WAVELENGTH,
+            Wavelengths::Spectral { lambda, .. } => lambda.x,
+        }
+    }
+
+    /// Values carried by the path of an RGB color (reflectance, emission).
+    pub fn of(&self, rgb: &Vector3<Real>) -> Vector3<Real> {
+        match self {
+            Wavelengths::Rgb => *rgb,
+            Wavelengths::Spectral { lambda, .. } => lambda.map(|lambda| upsample(rgb, lambda)),
+        }
+    }
+
+    /// Medium with coefficients carried by the path.
+    pub fn medium(&self, medium: &Medium) -> Medium {
+        Medium {
+            sigma_a: self.of(&medium.sigma_a),
+            sigma_s: self.of(&medium.sigma_s),
+            g: medium.g,
+        }
+    }
+
+    /// Keep only the hero wavelength, scattered alone by a dispersive surface,
+    /// with the weight which keeps the estimate of the path unbiased.
+    pub fn terminate_secondary(&self) -> (Self, Vector3<Real>) {
+        match self {
+            Wavelengths::Spectral {
+                lambda,
+                hero_only: false,
+            } => (
+                Wavelengths::Spectral {
+                    lambda: *lambda,
+                    hero_only: true,
+                },
+                Vector3::new(3., 0., 0.),
+            ),
+            _ => (*self, Vector3::repeat(1.)),
+        }
+    }
+
+    /// Linear RGB of the radiance carried by a path.
+    pub fn to_rgb(self, radiance: &Vector3<Real>) -> Vector3<Real> {
+        match self {
+            Wavelengths::Rgb => *radiance,
+            Wavelengths::Spectral { lambda, .. } => {
+                // Each wavelength was drawn uniformly over the visible range
+                let weight = (MAX_WAVELENGTH - MIN_WAVELENGTH) / 3.;
+                (0..3)
+                    .map(|i| rgb_response(lambda[i]) * radiance[i] * weight)
+                    .sum()
+            }
+        }
+    }
+}
+
+/// Value at `lambda` of the smooth spectrum of `rgb`, built from the spectra of
+/// white and of the primary and secondary colors.
+fn upsample(rgb: &Vector3<Real>, lambda: Real) -> Real {
+    let at = |spectrum: &[Real; 10]| interpolate(spectrum, lambda);
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+
+    if r <= g && r <= b {
+        r * at(&WHITE)
+            + if g <= b {
+                (g - r) * at(&CYAN) + (b - g) * at(&BLUE)
+            } else {
+                (b - r) * at(&CYAN) + (g - b) * at(&GREEN)
+            }
+    } else if g <= r && g <= b {
+        g * at(&WHITE)
+            + if r <= b {
+                (r - g) * at(&MAGENTA) + (b - r) * at(&BLUE)
+            } else {
+                (b - g) * at(&MAGENTA) + (r - b) * at(&RED)
+            }
+    } else {
+        b * at(&WHITE)
+            + if r <= g {
+                (r - b) * at(&YELLOW) + (g - r) * at(&GREEN)
+            } else {
+                (g - b) * at(&YELLOW) + (r - g) * at(&RED)
+            }
+    }
+}
+
+/// Linear interpolation of a spectrum sampled at the center of its bins.
+fn interpolate(spectrum: &[Real; 10], lambda: Real) -> Real {
+    let bin_width = (MAX_WAVELENGTH - MIN_WAVELENGTH) / spectrum.len() as Real;
+    let position =
+        ((lambda - MIN_WAVELENGTH) / bin_width - 0.5).clamp(0., (spectrum.len() - 1) as Real);
+    let bin = (position as usize).min(spectrum.len() - 2);
+    let fraction = position - bin as Real;
+
+    spectrum[bin] * (1. - fraction) + spectrum[bin + 1] * fraction
+}
+
+/// CIE 1931 color matching functions at `lambda`.
+fn xyz(lambda: Real) -> Vector3<Real> {
+    // Gaussian with a different width on each side of its mean
+    let g = |mean: Real, below: Real, above: Real| {
+        let t = (lambda - mean) / if lambda < mean { below } else { above };
+        (-0.5 * t * t).exp()
+    };
+
+    Vector3::new(
+        1.056 * g(599.8, 37.9, 31.) + 0.362 * g(442., 16., 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437., 11.8, 36.) + 0.681 * g(459., 26., 13.8),
+    )
+}
+
+/// Linear sRGB seen at `lambda`, scaled so a flat spectrum of 1 is white.
+fn rgb_response(lambda: Real) -> Vector3<Real> {
+    static WHITE_BALANCE: OnceLock<Vector3<Real>> = OnceLock::new();
+
+    let xyz_to_rgb = Matrix3::new(
+        3.2404542, -1.5371385, -0.4985314, -0.969266, 1.8760108, 0.041556, 0.0556434, -0.2040259,
+        1.0572252,
+    );
+    let white = WHITE_BALANCE.get_or_init(|| {
+        // Integral of the response over the visible range, by steps of 1nm
+        (MIN_WAVELENGTH as usize..MAX_WAVELENGTH as usize)
+            .map(|lambda| xyz_to_rgb * xyz(lambda as Real + 0.5))
+            .sum()
+    });
+
+    (xyz_to_rgb * xyz(lambda)).component_div(white)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Wavelengths every nanometer over the visible range
+    fn visible_range() -> impl Iterator<Item = Real> {
+        (MIN_WAVELENGTH as usize..=MAX_WAVELENGTH as usize).map(|lambda| lambda as Real)
+    }
+
+    #[test]
+    fn upsample_white_and_primaries() {
+        for lambda in visible_range() {
+            let white = upsample(&Vector3::repeat(1.), lambda);
+            assert!((white - 1.).abs() < 1e-3, "{} {}", lambda, white);
+
+            for rgb in [
+                Vector3::new(1., 0., 0.),
+                Vector3::new(0., 1., 0.),
+                Vector3::new(0., 0., 1.),
+                Vector3::new(0., 1., 1.),
+                Vector3::new(1., 0., 1.),
+                Vector3::new(1., 1., 0.),
+                Vector3::new(0.9, 0.3, 0.1),
+                Vector3::new(0.2, 0.5, 0.7),
+            ] {
+                let value = upsample(&rgb, lambda);
+                // Smits' spectra slightly overshoot 1, never 0
+                assert!(value >= 0., "{} {} {}", rgb, lambda, value);
+            }
+        }
+    }
+
+    #[test]
+    fn flat_spectrum_is_white() {
+        // Stratified wavelengths, each one carrying a flat spectrum of 1
+        let steps = 1000;
+        let rgb: Vector3<Real> = (0..steps)
+            .map(|i| {
+                let wavelengths = Wavelengths::sample((i as Real + 0.5) / steps as Real);
+                wavelengths.to_rgb(&Vector3::repeat(1.))
+            })
+            .sum::<Vector3<Real>>()
+            / steps as Real;
+
+        assert!((rgb - Vector3::repeat(1.)).amax() < 1e-2, "{}", rgb);
+    }
+}
@@ -0,0 +1,291 @@
+//! Subsurface scattering by random walk: light entering an object (skin, wax,
+//! marble) scatters through its inside, a homogeneous medium, until it leaves
+//! at another point of the surface.
+
+use nalgebra::Vector3;
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::engine::{Engine, RenderSettings, BSDF_DIMENSION, VOLUME_DIMENSION};
+use crate::medium::{sample_phase, FreeFlight, Media, Medium};
+use crate::objects::{HitRecord, ObjectsTrait};
+use crate::precision::{Real, RAY_EPSILON};
+use crate::rng::SampleRng;
+use crate::sampler::{bounce_dimension, Sampler};
+use crate::spectrum::Wavelengths;
+use crate::texture_material::Diffuse;
+use crate::Ray;
+
+// Scattering events after which a walk is given up, only materials absorbing
+// almost nothing lose a noticeable part of their light
+const MAX_WALK_STEPS: u32 = 256;
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct Subsurface {
+    // Average distance light travels inside the object between two interactions,
+    // per channel
+    pub mean_free_path: Vector3<Real>,
+    // Fraction of the light scattered at each interaction, the rest is absorbed
+    pub albedo: Vector3<Real>,
+    // Henyey-Greenstein asymmetry of the scattering
+    #[serde(default)]
+    pub g: Real,
+}
+
+impl Subsurface {
+    /// Medium filling the object.
+    pub fn medium(&self) -> Medium {
+        let sigma_t = self.mean_free_path.map(|distance| 1. / distance);
+        let sigma_s = sigma_t.component_mul(&self.albedo);
+
+        Medium {
+            sigma_a: sigma_t - sigma_s,
+            sigma_s,
+            g: self.g,
+        }
+    }
+}
+
+impl Engine {
+    /// Light leaving `object` at `point` towards the origin of the `bounce`-th ray
+    /// of a path, after entering there and walking through its inside. Light
+    /// enters and leaves through a diffuse surface, the walk only sees the
+    /// surface of the object.
+    #[allow(clippy::too_many_arguments)]
+    pub fn trace_subsurface(
+        &self,
+        settings: &RenderSettings,
+        object: &dyn ObjectsTrait,
+        subsurface: &Subsurface,
+        point: Vector3<Real>,
+        normal: Vector3<Real>,
+        bounce: u32,
+        throughput: Vector3<Real>,
+        sampler: &mut dyn Sampler,
+        wavelengths: &Wavelengths,
+    ) -> Vector3<Real> {
+        let dimension = bounce_dimension(bounce);
+        let medium = wavelengths.medium(&subsurface.medium());
+        let mut rng = SampleRng::from_sample(sampler.get_1d(dimension + VOLUME_DIMENSION));
+
+        // Light entering the object is spread in a cosine distribution, which
+        // `sample_hemisphere` gives from a cosine drawn as a square root
+        let (u1, u2) = sampler.get_2d(dimension + BSDF_DIMENSION);
+        let (direction, _) = self.sample_hemisphere(-normal, (u1.sqrt(), u2));
+        let ray = Ray::new(point - normal * RAY_EPSILON, direction);
+
+        match random_walk(object, &medium, ray, &mut rng) {
+            Some((exit, weight)) => {
+                let exitance = self.trace_subsurface_exit(
+                    settings,
+                    exit.point,
+                    exit.normal,
+                    bounce + 1,
+                    throughput.component_mul(&weight),
+                    sampler,
+                    rng,
+                    wavelengths,
+                );
+                weight.component_mul(&exitance)
+            }
+            None => Vector3::zeros(),
+        }
+    }
+
+    /// Light reaching the point where a walk leaves the object, through a diffuse
+    /// surface of normal `normal`: direct lighting, then the path continued like
+    /// after a diffuse bounce.
+    #[allow(clippy::too_many_arguments)]
+    fn trace_subsurface_exit(
+        &self,
+        settings: &RenderSettings,
+        point: Vector3<Real>,
+        normal: Vector3<Real>,
+        bounce: u32,
+        throughput: Vector3<Real>,
+        sampler: &mut dyn Sampler,
+        rng: SampleRng,
+        wavelengths: &Wavelengths,
+    ) -> Vector3<Real> {
+        if bounce >= settings.max_depth {
+            return Vector3::zeros();
+        }
+
+        let dimension = bounce_dimension(bounce);
+        let diffuse = Diffuse::new(1.);
+        let mut media = Media {
+            medium: self.medium.map(|medium| wavelengths.medium(&medium)),
+            rng,
+        };
+
+        // Like the emitters found by the bounce, which stops at the maximum depth
+        let direct_lightning = if bounce + 1 < settings.max_depth {
+            self.sample_direct_lightning(
+                settings,
+                point,
+                normal,
+                Some(&diffuse),
+                Some(&mut media),
+                wavelengths,
+                sampler,
+                dimension,
+            ) + self.point_lights_lightning(point, normal, Some(&mut media), wavelengths)
+        } else {
+            Vector3::zeros()
+        };
+
+        let (wi, cos_theta) =
+            self.sample_hemisphere(normal, sampler.get_2d(dimension + BSDF_DIMENSION));
+        let sample_ray = Ray::new(point + normal * RAY_EPSILON, wi);
+        let scattered = cos_theta
+            * self.trace_path(
+                settings,
+                &sample_ray,
+                bounce + 1,
+                throughput * cos_theta,
+                sampler,
+                Some(diffuse.pdf(&normal, &wi)),
+                wavelengths,
+            );
+
+        direct_lightning + scattered
+    }
+}
+
+/// Walk of light through the inside of `object` filled with `medium`, starting
+/// along `ray`. Gives the point where it leaves, with the outward normal there,
+/// and the weight of the light leaving, or `None` if the light is lost.
+pub fn random_walk(
+    object: &dyn ObjectsTrait,
+    medium: &Medium,
+    mut ray: Ray,
+    rng: &mut SampleRng,
+) -> Option<(HitRecord, Vector3<Real>)> {
+    let mut weight = Vector3::repeat(1.);
+
+    for _ in 0..MAX_WALK_STEPS {
+        // An open mesh lets the walk escape, its light is lost
+        let record = object.intersects(&ray, RAY_EPSILON, Real::INFINITY)?;
+
+        match medium.sample_free_flight(record.t, rng.gen()) {
+            FreeFlight::Scattered {
+                t, g, weight: w, ..
+            } => {
+                weight.component_mul_assign(&w);
+                let (wi, _) = sample_phase(g, &ray.direction, (rng.gen(), rng.gen()));
+                ray = Ray::new(ray.at(t), wi);
+            }
+            FreeFlight::Passed { weight: w } => {
+                weight.component_mul_assign(&w);
+                let outward = if record.normal.dot(&ray.direction) > 0. {
+                    record.normal
+                } else {
+                    -record.normal
+                };
+                return Some((HitRecord::new(record.t, record.point, outward), weight));
+            }
+        }
+
+        // Walks losing their weight to absorption are ended by russian roulette
+        let survival = weight.max().min(1.);
+        if rng.gen::<Real>() >= survival {
+            return None;
+        }
+        weight /= survival;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::{MeshConfig, ObjData};
+    use crate::objects::{Mesh, Sphere};
+
+    /// Average weight of walks started from the center of `object`, checking that
+    /// each one leaves on the surface `on_surface` with a weight of 1.
+    fn mean_exit_weight(
+        object: &dyn ObjectsTrait,
+        on_surface: impl Fn(&Vector3<Real>) -> bool,
+    ) -> Real {
+        // Scatters only, once per 0.2 on average
+        let subsurface = Subsurface {
+            mean_free_path: Vector3::repeat(0.2),
+            albedo: Vector3::repeat(1.),
+            g: 0.3,
+        };
+        let medium = subsurface.medium();
+        let mut rng = SampleRng::new(5, 0, 0, 0);
+
+        let walks = 2000;
+        let mut total = 0.;
+        for _ in 0..walks {
+            let direction = Vector3::new(rng.gen(), rng.gen(), rng.gen())
+                .map(|x: Real| x - 0.5)
+                .normalize();
+            if let Some((exit, weight)) = random_walk(
+                object,
+                &medium,
+                Ray::new(Vector3::zeros(), direction),
+                &mut rng,
+            ) {
+                assert!((weight - Vector3::repeat(1.)).amax() < 1e-6, "{}", weight);
+                assert!(on_surface(&exit.point), "{}", exit.point);
+                assert!(exit.normal.dot(&exit.point) > 0., "{}", exit.normal);
+                total += weight.x;
+            }
+        }
+
+        total / walks as Real
+    }
+
+    #[test]
+    fn walks_without_absorption_keep_their_light() {
+        let sphere = Sphere {
+            center: Vector3::zeros(),
+            radius: 1.,
+            textmat: Default::default(),
+        };
+        let mean = mean_exit_weight(&sphere, |point| (point.norm() - 1.).abs() < 1e-4);
+        // Only the few walks cut by `MAX_WALK_STEPS` are lost
+        assert!(mean > 0.99 && mean <= 1., "{}", mean);
+    }
+
+    #[test]
+    fn walks_leave_closed_meshes() {
+        // Cube of side 2 centered on the origin
+        let obj = ObjData {
+            vertices: (0..8)
+                .map(|i| {
+                    Vector3::new(i & 1, (i >> 1) & 1, (i >> 2) & 1).map(|x| 2. * x as Real - 1.)
+                })
+                .collect(),
+            indices: vec![
+                [0, 2, 1],
+                [1, 2, 3],
+                [4, 5, 6],
+                [5, 7, 6],
+                [0, 1, 4],
+                [1, 5, 4],
+                [2, 6, 3],
+                [3, 6, 7],
+                [0, 4, 2],
+                [2, 4, 6],
+                [1, 3, 5],
+                [3, 7, 5],
+            ],
+        };
+        let config = MeshConfig {
+            path: String::new(),
+            origin: Vector3::zeros(),
+            scale: 1.,
+            rotation: Vector3::zeros(),
+            textmat: Default::default(),
+        };
+        let mesh = Mesh::new(config.triangularization(obj), Default::default());
+
+        let mean = mean_exit_weight(&mesh, |point| (point.amax() - 1.).abs() < 1e-4);
+        assert!(mean > 0.99 && mean <= 1., "{}", mean);
+    }
+}
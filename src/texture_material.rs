@@ -1,9 +1,13 @@
 use nalgebra::Vector3;
 use serde::Deserialize;
 
+use crate::medium::Medium;
+use crate::precision::{consts::PI, Real};
+use crate::subsurface::Subsurface;
+
 #[derive(Copy, Clone, Debug, Deserialize)]
 pub struct TextureMaterial {
-    pub color: Vector3<f64>,
+    pub color: Vector3<Real>,
     #[serde(default)]
     pub surface: Surface,
 }
@@ -24,6 +28,12 @@ pub struct Surface {
     pub specular: Specular,
     pub reflection: Reflection,
     pub transmission: Transmission,
+    // Medium filling the inside of a closed object
+    #[serde(default)]
+    pub medium: Option<Medium>,
+    // Scattering inside a closed object, replacing the diffuse reflection
+    #[serde(default)]
+    pub subsurface: Option<Subsurface>,
 }
 
 impl Default for Surface {
@@ -34,56 +44,110 @@ impl Default for Surface {
             specular: Specular::new(1.0, 15.0),
             reflection: Reflection::new(0.5),
             transmission: Transmission::new(0.5),
+            medium: None,
+            subsurface: None,
         }
     }
 }
 
 #[derive(Copy, Clone, Debug, Deserialize)]
 pub struct Emittance {
-    pub ke: f64,
+    pub ke: Real,
 }
 
 #[derive(Copy, Clone, Debug, Deserialize)]
 pub struct Diffuse {
-    pub kd: f64,
+    pub kd: Real,
 }
 
 impl Diffuse {
-    pub fn new(kd: f64) -> Self {
+    pub fn new(kd: Real) -> Self {
         Self { kd }
     }
+
+    /// Density of the directions picked by the diffuse bounce, uniform over the
+    /// hemisphere around `normal`.
+    pub fn pdf(&self, normal: &Vector3<Real>, direction: &Vector3<Real>) -> Real {
+        if normal.dot(direction) > 0. {
+            1. / (2. * PI)
+        } else {
+            0.
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, Deserialize)]
 pub struct Specular {
-    pub ks: f64,
-    pub ns: f64,
+    pub ks: Real,
+    pub ns: Real,
 }
 
 impl Specular {
-    pub fn new(ks: f64, ns: f64) -> Self {
+    pub fn new(ks: Real, ns: Real) -> Self {
         Self { ks, ns }
     }
 }
 
 #[derive(Copy, Clone, Debug, Deserialize)]
 pub struct Reflection {
-    pub kr: f64,
+    pub kr: Real,
 }
 
 impl Reflection {
-    pub fn new(kr: f64) -> Self {
+    pub fn new(kr: Real) -> Self {
         Self { kr }
     }
 }
 
+// Index of refraction of transparent surfaces without dispersion
+const DEFAULT_IOR: Real = 1.5;
+
 #[derive(Copy, Clone, Debug, Deserialize)]
 pub struct Transmission {
-    pub kt: f64,
+    pub kt: Real,
+    // Index of refraction varying with the wavelength, which disperses light in
+    // spectral renders
+    #[serde(default)]
+    pub ior: Option<Ior>,
 }
 
 impl Transmission {
-    pub fn new(kt: f64) -> Self {
-        Self { kt }
+    pub fn new(kt: Real) -> Self {
+        Self { kt, ior: None }
+    }
+
+    /// Index of refraction at `wavelength`, in nanometers.
+    pub fn ior(&self, wavelength: Real) -> Real {
+        match self.ior {
+            Some(ior) => ior.at(wavelength),
+            None => DEFAULT_IOR,
+        }
+    }
+}
+
+/// Models of the index of refraction of a material against the wavelength,
+/// in micrometers.
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ior {
+    // n = a + b / l^2
+    Cauchy { a: Real, b: Real },
+    // n^2 = 1 + sum of b_i l^2 / (l^2 - c_i)
+    Sellmeier { b: [Real; 3], c: [Real; 3] },
+}
+
+impl Ior {
+    pub fn at(&self, wavelength: Real) -> Real {
+        let micrometers = wavelength / 1000.;
+        let squared = micrometers * micrometers;
+
+        match self {
+            Ior::Cauchy { a, b } => a + b / squared,
+            Ior::Sellmeier { b, c } => (1.
+                + (0..3)
+                    .map(|i| b[i] * squared / (squared - c[i]))
+                    .sum::<Real>())
+            .sqrt(),
+        }
     }
 }
@@ -0,0 +1,361 @@
+//! Heterogeneous volumes: densities (smoke, clouds) and emission (fire) given by
+//! dense voxel grids stretched over a box of the scene. Rays are tracked through
+//! them against the largest density of the box (delta and ratio tracking), so
+//! the grid is never marched step by step.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+
+use nalgebra::Vector3;
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::bvh::Aabb;
+use crate::cache::read_u32;
+use crate::precision::Real;
+use crate::rng::SampleRng;
+use crate::Ray;
+
+// Header of the Mitsuba volume format, followed by its version
+const MAGIC: &[u8; 3] = b"VOL";
+const VERSION: u8 = 3;
+// Encoding of the voxels, only 32 bit floats are supported
+const FLOAT32_ENCODING: u32 = 1;
+// Voxels allocated up front, larger grids grow as they are read
+const MAX_PREALLOCATED_VALUES: usize = 1 << 24;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct VolumeConfig {
+    // Density grid, in the Mitsuba `.vol` format
+    pub path: String,
+    // Box the grid is stretched over
+    pub min: Vector3<Real>,
+    pub max: Vector3<Real>,
+    // Extinction coefficient of a voxel of density 1
+    pub density_scale: Real,
+    // Fraction of the light interacting with the volume which is scattered,
+    // the rest is absorbed
+    pub albedo: Vector3<Real>,
+    // Henyey-Greenstein asymmetry of the scattering
+    #[serde(default)]
+    pub g: Real,
+    #[serde(default)]
+    pub emission: Option<EmissionConfig>,
+}
+
+/// Radiance emitted where light is absorbed, from a grid of 1 (grey) or 3
+/// (RGB) channels.
+#[derive(Clone, Debug, Deserialize)]
+pub struct EmissionConfig {
+    pub path: String,
+    pub scale: Real,
+}
+
+impl VolumeConfig {
+    pub fn load(&self) -> Volume {
+        let load_grid = |path: &str| match VoxelGrid::load(path) {
+            Ok(grid) => grid,
+            Err(error) => panic!("Problem in parsing volume file '{}': {:?}'", path, error),
+        };
+
+        let density = load_grid(&self.path);
+        let majorant = density.max_value() * self.density_scale;
+
+        Volume {
+            bounds: Aabb::new(self.min, self.max),
+            density,
+            density_scale: self.density_scale,
+            majorant,
+            albedo: self.albedo,
+            g: self.g,
+            emission: self
+                .emission
+                .as_ref()
+                .map(|emission| (load_grid(&emission.path), emission.scale)),
+        }
+    }
+}
+
+/// Values sampled on a regular grid, the first and last voxels of each axis
+/// lying on the faces of the box.
+pub struct VoxelGrid {
+    resolution: [usize; 3],
+    channels: usize,
+    // Channels of each voxel, x varying fastest then y then z
+    values: Vec<Real>,
+}
+
+impl VoxelGrid {
+    pub fn load(path: &str) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic[..3] != MAGIC || magic[3] != VERSION {
+            return Err(invalid_data("not a version 3 Mitsuba volume"));
+        }
+        if read_u32(&mut reader)? != FLOAT32_ENCODING {
+            return Err(invalid_data("voxels must be 32 bit floats"));
+        }
+
+        let resolution = [
+            read_u32(&mut reader)? as usize,
+            read_u32(&mut reader)? as usize,
+            read_u32(&mut reader)? as usize,
+        ];
+        let channels = read_u32(&mut reader)? as usize;
+        if resolution.contains(&0) || !(channels == 1 || channels == 3) {
+            return Err(invalid_data("empty grid or unsupported channel count"));
+        }
+
+        // Bounding box of the file, placement is given by the scene instead
+        for _ in 0..6 {
+            read_f32(&mut reader)?;
+        }
+
+        let count = resolution
+            .iter()
+            .try_fold(channels, |count, &size| count.checked_mul(size))
+            .ok_or_else(|| invalid_data("grid too large"))?;
+        // The header isn't trusted with the allocation, a truncated file fails
+        // before the vector grows past its size
+        let mut values = Vec::with_capacity(count.min(MAX_PREALLOCATED_VALUES));
+        for _ in 0..count {
+            values.push(read_f32(&mut reader)? as Real);
+        }
+
+        Ok(Self {
+            resolution,
+            channels,
+            values,
+        })
+    }
+
+    pub fn max_value(&self) -> Real {
+        self.values.iter().copied().fold(0., Real::max)
+    }
+
+    /// Trilinear interpolation at `position`, in [0, 1]^3 over the grid. Grey
+    /// grids give the same value on every channel.
+    pub fn lookup(&self, position: &Vector3<Real>) -> Vector3<Real> {
+        let mut corner = [0; 3];
+        let mut fraction = [0.; 3];
+        for axis in 0..3 {
+            let last = self.resolution[axis] - 1;
+            let coordinate = (position[axis] * last as Real).clamp(0., last as Real);
+            corner[axis] = (coordinate as usize).min(last.saturating_sub(1));
+            fraction[axis] = coordinate - corner[axis] as Real;
+        }
+
+        let mut value = Vector3::zeros();
+        for neighbour in 0..8 {
+            let mut weight = 1.;
+            let mut voxel = [0; 3];
+            for axis in 0..3 {
+                let step = (neighbour >> axis) & 1;
+                voxel[axis] = (corner[axis] + step).min(self.resolution[axis] - 1);
+                weight *= if step == 1 {
+                    fraction[axis]
+                } else {
+                    1. - fraction[axis]
+                };
+            }
+            if weight > 0. {
+                value += self.voxel(voxel) * weight;
+            }
+        }
+
+        value
+    }
+
+    fn voxel(&self, [x, y, z]: [usize; 3]) -> Vector3<Real> {
+        let index = ((z * self.resolution[1] + y) * self.resolution[0] + x) * self.channels;
+        if self.channels == 1 {
+            Vector3::repeat(self.values[index])
+        } else {
+            Vector3::new(
+                self.values[index],
+                self.values[index + 1],
+                self.values[index + 2],
+            )
+        }
+    }
+}
+
+pub struct Volume {
+    bounds: Aabb,
+    density: VoxelGrid,
+    density_scale: Real,
+    // Largest extinction coefficient in the box, rate of the tentative collisions
+    majorant: Real,
+    pub albedo: Vector3<Real>,
+    pub g: Real,
+    // Emitted radiance and its scale
+    emission: Option<(VoxelGrid, Real)>,
+}
+
+impl Volume {
+    /// Extinction coefficient at `point`, from the first channel of the density.
+    fn extinction(&self, point: &Vector3<Real>) -> Real {
+        self.density.lookup(&self.local(point)).x * self.density_scale
+    }
+
+    fn local(&self, point: &Vector3<Real>) -> Vector3<Real> {
+        (point - self.bounds.min).component_div(&(self.bounds.max - self.bounds.min))
+    }
+
+    /// Part of `ray` before `max_t` crossing the box.
+    fn range(&self, ray: &Ray, max_t: Real) -> Option<(Real, Real)> {
+        let inv_direction = ray.direction.map(|d| 1. / d);
+        self.bounds.range(ray, &inv_direction, 0., max_t)
+    }
+
+    /// Distance along `ray` of its first collision with the volume before
+    /// `max_t` (delta tracking). Tentative collisions are drawn at the rate of
+    /// the majorant and kept in proportion to the density where they land.
+    pub fn sample_collision(&self, ray: &Ray, max_t: Real, rng: &mut SampleRng) -> Option<Real> {
+        if self.majorant <= 0. {
+            return None;
+        }
+        let (mut t, end) = self.range(ray, max_t)?;
+
+        loop {
+            t -= (1. - rng.gen::<Real>()).ln() / self.majorant;
+            if t >= end {
+                return None;
+            }
+            if self.extinction(&ray.at(t)) > rng.gen::<Real>() * self.majorant {
+                return Some(t);
+            }
+        }
+    }
+
+    /// Estimate of the fraction of light going through the volume along the first
+    /// `max_t` of `ray` (ratio tracking).
+    pub fn transmittance(&self, ray: &Ray, max_t: Real, rng: &mut SampleRng) -> Real {
+        if self.majorant <= 0. {
+            return 1.;
+        }
+        let Some((mut t, end)) = self.range(ray, max_t) else {
+            return 1.;
+        };
+
+        let mut transmittance = 1.;
+        loop {
+            t -= (1. - rng.gen::<Real>()).ln() / self.majorant;
+            if t >= end || transmittance <= 0. {
+                return transmittance;
+            }
+            transmittance *= 1. - self.extinction(&ray.at(t)) / self.majorant;
+        }
+    }
+
+    /// Light emitted at a collision at `point`, the absorbed part of the light
+    /// interacting with the volume being given back as emission.
+    pub fn emission(&self, point: &Vector3<Real>) -> Vector3<Real> {
+        match &self.emission {
+            Some((grid, scale)) => {
+                (Vector3::repeat(1.) - self.albedo).component_mul(&grid.lookup(&self.local(point)))
+                    * *scale
+            }
+            None => Vector3::zeros(),
+        }
+    }
+}
+
+fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    // Header of a 2x1x1 grid with the given magic, version, encoding and channels
+    fn header(magic: &[u8; 3], version: u8, encoding: u32, channels: u32) -> Vec<u8> {
+        let mut bytes = magic.to_vec();
+        bytes.push(version);
+        for value in [encoding, 2, 1, 1, channels] {
+            bytes.extend(value.to_le_bytes());
+        }
+        for value in [0f32, 0., 0., 1., 1., 1.] {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn load(name: &str, bytes: &[u8]) -> io::Result<VoxelGrid> {
+        let path = env::temp_dir().join(format!("raytracer-test-{}.vol", name));
+        fs::write(&path, bytes).unwrap();
+        let result = VoxelGrid::load(&path.to_string_lossy());
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn voxels(values: &[f32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn load_grid() {
+        let grid = load(
+            "grid",
+            &[header(MAGIC, VERSION, 1, 1), voxels(&[0.5, 1.5])].concat(),
+        )
+        .unwrap();
+        assert_eq!(grid.resolution, [2, 1, 1]);
+        assert_eq!(grid.max_value(), 1.5);
+        assert_eq!(grid.lookup(&Vector3::new(0.5, 0., 0.)), Vector3::repeat(1.));
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let values = voxels(&[0.; 6]);
+        for (name, header) in [
+            ("magic", header(b"VOX", VERSION, 1, 1)),
+            ("version", header(MAGIC, 2, 1, 1)),
+            ("encoding", header(MAGIC, VERSION, 2, 1)),
+            ("channels", header(MAGIC, VERSION, 1, 2)),
+        ] {
+            let error = load(name, &[header, values.clone()].concat())
+                .err()
+                .unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", name);
+        }
+
+        let mut empty = header(MAGIC, VERSION, 1, 1);
+        empty[8..12].copy_from_slice(&0u32.to_le_bytes());
+        let error = load("empty", &empty).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // Header announcing more voxels than the file holds
+        let truncated = [header(MAGIC, VERSION, 1, 3), voxels(&[0.; 4])].concat();
+        let error = load("truncated", &truncated).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        // Headers announcing more voxels than can be counted, or than fit in memory
+        for (name, size, kind) in [
+            ("overflow", u32::MAX, io::ErrorKind::InvalidData),
+            ("oversized", 1 << 20, io::ErrorKind::UnexpectedEof),
+        ] {
+            let mut oversized = header(MAGIC, VERSION, 1, 3);
+            for axis in 0..3 {
+                oversized[8 + 4 * axis..12 + 4 * axis].copy_from_slice(&size.to_le_bytes());
+            }
+            let error = load(name, &[oversized, values.clone()].concat())
+                .err()
+                .unwrap();
+            assert_eq!(error.kind(), kind, "{}", name);
+        }
+    }
+}